@@ -0,0 +1,273 @@
+//! Chess clocks and time controls.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use colored::*;
+
+use crate::Side;
+
+/// Time added for every move made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bonus {
+    /// Sudden death, no time is ever added.
+    None,
+    /// Fischer increment, always added after a move.
+    Increment(Duration),
+    /// Bronstein delay, the time used for a move is given back up to this
+    /// amount.
+    Delay(Duration),
+}
+
+/// A time control like `5+3`: 5 minutes for the game with an increment of 3
+/// seconds per move. `5d3` uses a Bronstein delay instead, `5` is sudden
+/// death.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControl {
+    pub base: Duration,
+    pub bonus: Bonus,
+}
+
+impl FromStr for TimeControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = |s: &str, unit: f64| {
+            // rejects negative times and ones too long for a `Duration`
+            s.parse::<f64>()
+                .ok()
+                .and_then(|value| Duration::try_from_secs_f64(value * unit).ok())
+                .ok_or_else(|| format!("invalid time control {:?}, expected e.g. 5+3", s))
+        };
+
+        let (base, bonus) = if let Some((base, increment)) = s.split_once('+') {
+            (base, Bonus::Increment(seconds(increment, 1.0)?))
+        } else if let Some((base, delay)) = s.split_once('d') {
+            (base, Bonus::Delay(seconds(delay, 1.0)?))
+        } else {
+            (s, Bonus::None)
+        };
+
+        let base = seconds(base, 60.0)?;
+
+        if base.is_zero() {
+            return Err("a time control needs some time on the clock".to_string());
+        }
+
+        Ok(TimeControl { base, bonus })
+    }
+}
+
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.base.as_secs_f64() / 60.0)?;
+
+        match self.bonus {
+            Bonus::None => Ok(()),
+            Bonus::Increment(increment) => write!(f, "+{}", increment.as_secs_f64()),
+            Bonus::Delay(delay) => write!(f, "d{}", delay.as_secs_f64()),
+        }
+    }
+}
+
+/// A clock for both players, of which at most one is running.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    control: TimeControl,
+    /// Time left for both sides, indexed by `Side`, as of the last time the
+    /// running clock was stopped.
+    remaining: [Duration; 2],
+    /// The side whose clock is running, and since when.
+    running: Option<(Side, Instant)>,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Clock {
+        Clock {
+            control,
+            remaining: [control.base; 2],
+            running: None,
+        }
+    }
+
+    /// Starts the clock of `side`, stopping the other one without a bonus.
+    pub fn start(&mut self, side: Side) {
+        self.stop();
+        self.running = Some((side, Instant::now()));
+    }
+
+    pub fn stop(&mut self) {
+        if let Some((side, since)) = self.running.take() {
+            self.remaining[side as usize] =
+                self.remaining[side as usize].saturating_sub(since.elapsed());
+        }
+    }
+
+    /// Ends the turn of the running side: its clock is stopped, the bonus of
+    /// the time control is added unless its flag fell, and the clock of the
+    /// opponent is started.
+    pub fn press(&mut self) {
+        let Some((side, since)) = self.running else {
+            return;
+        };
+
+        let used = since.elapsed();
+        let left = self.remaining[side as usize].saturating_sub(used);
+
+        let bonus = match self.control.bonus {
+            Bonus::None => Duration::ZERO,
+            Bonus::Increment(increment) => increment,
+            Bonus::Delay(delay) => delay.min(used),
+        };
+
+        self.remaining[side as usize] = if left.is_zero() { left } else { left + bonus };
+        self.running = Some((side.opponent(), Instant::now()));
+    }
+
+    /// Overwrites the time left of both sides, e.g. with the values
+    /// reported by the opponent.
+    pub fn set(&mut self, white: Duration, black: Duration) {
+        self.remaining = [white, black];
+
+        if let Some((_, since)) = &mut self.running {
+            *since = Instant::now();
+        }
+    }
+
+    pub fn remaining(&self, side: Side) -> Duration {
+        let remaining = self.remaining[side as usize];
+
+        match self.running {
+            Some((running, since)) if running == side => remaining.saturating_sub(since.elapsed()),
+            _ => remaining,
+        }
+    }
+
+    /// The side that ran out of time, if any.
+    pub fn flagged(&self) -> Option<Side> {
+        [Side::White, Side::Black]
+            .into_iter()
+            .find(|&side| self.remaining(side).is_zero())
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for side in [Side::White, Side::Black] {
+            let remaining = self.remaining(side);
+            let time = format_time(remaining);
+
+            let time = match self.running {
+                Some((running, _)) if running == side => time.bold().reversed(),
+                _ if remaining.is_zero() => time.red(),
+                _ => time.normal(),
+            };
+
+            write!(f, "{} {}  ", side, time)?;
+        }
+
+        write!(f, "({})", self.control)
+    }
+}
+
+/// Formats time left on a clock as `m:ss`, with tenths below ten seconds.
+pub fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+
+    if seconds < 10 {
+        format!(
+            "{}:{:02}.{}",
+            seconds / 60,
+            seconds % 60,
+            time.subsec_millis() / 100
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock for `control` whose white player pressed it after thinking
+    /// for `used`.
+    fn pressed_after(control: &str, used: Duration) -> Clock {
+        let mut clock = Clock::new(control.parse().unwrap());
+        clock.running = Some((Side::White, Instant::now() - used));
+        clock.press();
+        clock
+    }
+
+    #[test]
+    fn parses_time_controls() {
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+
+        assert_eq!(
+            "5".parse(),
+            Ok(TimeControl {
+                base: minutes(5),
+                bonus: Bonus::None,
+            })
+        );
+        assert_eq!(
+            "5+3".parse(),
+            Ok(TimeControl {
+                base: minutes(5),
+                bonus: Bonus::Increment(Duration::from_secs(3)),
+            })
+        );
+        assert_eq!(
+            "5d3".parse(),
+            Ok(TimeControl {
+                base: minutes(5),
+                bonus: Bonus::Delay(Duration::from_secs(3)),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_time_controls() {
+        for control in ["0", "-1", "1e300", "5+1e300", "5+-1", "", "5+", "five"] {
+            assert!(control.parse::<TimeControl>().is_err(), "{:?}", control);
+        }
+    }
+
+    #[test]
+    fn time_controls_round_trip() {
+        for control in ["5", "5+3", "5d3", "0.5+0.5", "90+30"] {
+            let parsed = control.parse::<TimeControl>().unwrap();
+
+            assert_eq!(parsed.to_string(), control);
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+    }
+
+    #[test]
+    fn press_adds_the_bonus_and_starts_the_opponent() {
+        let clock = pressed_after("5+3", Duration::from_secs(10));
+        let left = clock.remaining(Side::White);
+        assert!(left > Duration::from_secs(292) && left <= Duration::from_secs(293));
+        assert_eq!(clock.running.map(|(side, _)| side), Some(Side::Black));
+    }
+
+    #[test]
+    fn delay_gives_back_no_more_than_the_time_used() {
+        let clock = pressed_after("5d3", Duration::from_secs(2));
+        assert_eq!(clock.remaining(Side::White), Duration::from_secs(300));
+
+        let clock = pressed_after("5d3", Duration::from_secs(10));
+        let left = clock.remaining(Side::White);
+        assert!(left > Duration::from_secs(292) && left <= Duration::from_secs(293));
+    }
+
+    #[test]
+    fn no_bonus_once_the_flag_fell() {
+        let clock = pressed_after("0.01+5", Duration::from_secs(1));
+        assert_eq!(clock.remaining(Side::White), Duration::ZERO);
+        assert_eq!(clock.flagged(), Some(Side::White));
+    }
+}
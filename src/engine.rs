@@ -0,0 +1,458 @@
+//! The built-in computer opponent: an alpha-beta search over the legal moves
+//! of a `Position`, scored by material and the placement of the pieces.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    position::{Kind, Move, Position, Square},
+    Side,
+};
+
+/// The score of being mated right now. Mates further away score a little
+/// less, so the engine goes for the quickest one.
+pub const MATE: i32 = 30_000;
+const INFINITY: i32 = 32_000;
+
+/// How long and how deep to search, and how many mistakes to make on
+/// purpose.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub depth: u32,
+    pub time: Option<Duration>,
+    /// Up to this many centipawns are randomly added to or taken from the
+    /// score of every move, so close moves get mixed up.
+    pub noise: i32,
+    /// The chance of playing a random move instead of searching at all.
+    pub blunders: f64,
+}
+
+/// The weakest and strongest levels of the engine.
+pub const LEVELS: std::ops::RangeInclusive<u8> = 1..=10;
+
+impl Limits {
+    /// The limits of a playing strength in `LEVELS`: deeper searches and
+    /// fewer mistakes with every level.
+    pub fn level(level: u8) -> Limits {
+        let (depth, millis, noise, blunders) = match level.clamp(*LEVELS.start(), *LEVELS.end()) {
+            1 => (1, 100, 300, 0.3),
+            2 => (1, 200, 200, 0.2),
+            3 => (2, 300, 150, 0.15),
+            4 => (2, 500, 100, 0.1),
+            5 => (3, 700, 60, 0.05),
+            6 => (3, 1000, 30, 0.02),
+            7 => (4, 1500, 15, 0.0),
+            8 => (5, 2000, 0, 0.0),
+            9 => (6, 3000, 0, 0.0),
+            _ => (8, 5000, 0, 0.0),
+        };
+
+        Limits {
+            depth,
+            time: Some(Duration::from_millis(millis)),
+            noise,
+            blunders,
+        }
+    }
+}
+
+/// A score as engines report it, from the point of view of the side to
+/// move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Score {
+    Centipawns(i32),
+    /// Mate in this many moves, negative when the side to move gets mated.
+    Mate(i32),
+}
+
+impl Score {
+    /// The score of a search result.
+    pub fn new(score: i32) -> Score {
+        if score.abs() > MATE - 1000 {
+            let moves = (MATE - score.abs() + 1) / 2;
+            Score::Mate(moves * score.signum())
+        } else {
+            Score::Centipawns(score)
+        }
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Score::Centipawns(centipawns) => write!(f, "{:+.2}", *centipawns as f64 / 100.0),
+            Score::Mate(moves) => write!(f, "#{}", moves),
+        }
+    }
+}
+
+/// What the search found after completing a depth.
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub depth: u32,
+    /// In centipawns, from the point of view of the side to move.
+    pub score: i32,
+    pub nodes: u64,
+    pub time: Duration,
+    /// The line the engine expects to be played, starting with its move.
+    pub pv: Vec<Move>,
+}
+
+/// Finds the best move in `position` within `limits`, or until `stop` is
+/// set, calling `report` after every completed depth. `None` when there is
+/// no legal move.
+pub fn search(
+    position: &Position,
+    limits: Limits,
+    stop: &AtomicBool,
+    mut report: impl FnMut(&Info),
+) -> Option<Move> {
+    let moves = position.legal_moves();
+    let mut best = *moves.first()?;
+
+    let mut rng = rand::thread_rng();
+    if rng.gen_bool(limits.blunders.clamp(0.0, 1.0)) {
+        return Some(moves[rng.gen_range(0..moves.len())]);
+    }
+
+    let mut search = Search {
+        started: Instant::now(),
+        deadline: limits.time.map(|time| Instant::now() + time),
+        stop,
+        noise: limits.noise.max(0),
+        nodes: 0,
+        stopped: false,
+        pv: Vec::new(),
+    };
+
+    for depth in 1..=limits.depth.max(1) {
+        let mut pv = Vec::new();
+        let score = search.negamax(position, depth, 0, -INFINITY, INFINITY, &mut pv);
+
+        // an unfinished depth has not looked at every move
+        if search.stopped || pv.is_empty() {
+            break;
+        }
+
+        best = pv[0];
+        search.pv = pv.clone();
+
+        report(&Info {
+            depth,
+            score,
+            nodes: search.nodes,
+            time: search.started.elapsed(),
+            pv,
+        });
+
+        if score.abs() > MATE - 1000 {
+            break;
+        }
+    }
+
+    Some(best)
+}
+
+struct Search<'a> {
+    started: Instant,
+    deadline: Option<Instant>,
+    stop: &'a AtomicBool,
+    noise: i32,
+    nodes: u64,
+    stopped: bool,
+    /// The best line of the previous depth, searched first.
+    pv: Vec<Move>,
+}
+
+impl Search<'_> {
+    fn negamax(
+        &mut self,
+        position: &Position,
+        depth: u32,
+        ply: usize,
+        mut alpha: i32,
+        beta: i32,
+        pv: &mut Vec<Move>,
+    ) -> i32 {
+        pv.clear();
+
+        if depth == 0 {
+            return self.quiesce(position, alpha, beta);
+        }
+
+        if self.out_of_time() {
+            return 0;
+        }
+
+        self.nodes += 1;
+
+        let mut moves = position.legal_moves();
+
+        if moves.is_empty() {
+            return if position.in_check() {
+                -(MATE - ply as i32)
+            } else {
+                0
+            };
+        }
+
+        if position.halfmove >= 100 {
+            return 0;
+        }
+
+        self.order(position, &mut moves, self.pv.get(ply).copied());
+
+        let mut line = Vec::new();
+
+        for mv in moves {
+            let mut next = position.clone();
+            next.play(mv);
+
+            let mut score = -self.negamax(&next, depth - 1, ply + 1, -beta, -alpha, &mut line);
+
+            if self.stopped {
+                return 0;
+            }
+
+            // misjudge the moves of the engine itself, not the replies
+            if ply == 0 && self.noise > 0 {
+                score += rand::thread_rng().gen_range(-self.noise..=self.noise);
+            }
+
+            if score > alpha {
+                alpha = score;
+
+                pv.clear();
+                pv.push(mv);
+                pv.extend_from_slice(&line);
+
+                if alpha >= beta {
+                    break;
+                }
+            }
+        }
+
+        alpha
+    }
+
+    /// Searches captures only, so positions are never scored in the middle
+    /// of an exchange.
+    fn quiesce(&mut self, position: &Position, mut alpha: i32, beta: i32) -> i32 {
+        self.nodes += 1;
+
+        let standing = evaluate(position);
+        if standing >= beta {
+            return beta;
+        }
+        alpha = alpha.max(standing);
+
+        let mut captures = position.legal_moves();
+        captures.retain(|&mv| position.is_capture(mv) || mv.promotion.is_some());
+        self.order(position, &mut captures, None);
+
+        for mv in captures {
+            let mut next = position.clone();
+            next.play(mv);
+
+            let score = -self.quiesce(&next, -beta, -alpha);
+
+            if score >= beta {
+                return beta;
+            }
+            alpha = alpha.max(score);
+        }
+
+        alpha
+    }
+
+    /// Puts the moves most likely to be good first, which lets alpha-beta
+    /// skip more of the others: `first`, then captures of valuable pieces
+    /// by cheap ones.
+    fn order(&self, position: &Position, moves: &mut [Move], first: Option<Move>) {
+        moves.sort_by_cached_key(|&mv| {
+            if Some(mv) == first {
+                return i32::MIN;
+            }
+
+            let victim = match position.piece_at(mv.to) {
+                Some(piece) => value(piece.kind),
+                None if position.is_capture(mv) => value(Kind::Pawn),
+                None => 0,
+            };
+            let attacker = position
+                .piece_at(mv.from)
+                .map_or(0, |piece| value(piece.kind));
+            let promotion = mv.promotion.map_or(0, value);
+
+            if victim > 0 {
+                -(10 * victim - attacker + promotion)
+            } else {
+                -promotion
+            }
+        });
+    }
+
+    fn out_of_time(&mut self) -> bool {
+        if let Some(deadline) = self.deadline {
+            self.stopped |= Instant::now() >= deadline;
+        }
+
+        self.stopped |= self.stop.load(Ordering::Relaxed);
+
+        self.stopped
+    }
+}
+
+fn value(kind: Kind) -> i32 {
+    match kind {
+        Kind::Pawn => 100,
+        Kind::Knight => 320,
+        Kind::Bishop => 330,
+        Kind::Rook => 500,
+        Kind::Queen => 900,
+        Kind::King => 0,
+    }
+}
+
+/// Scores `position` from the point of view of the side to move.
+pub fn evaluate(position: &Position) -> i32 {
+    // without queens, or with little else left, kings belong in the center
+    let officers: i32 = position
+        .pieces()
+        .filter(|(_, piece)| !matches!(piece.kind, Kind::Pawn | Kind::King))
+        .map(|(_, piece)| value(piece.kind))
+        .sum();
+    let queens = position
+        .pieces()
+        .any(|(_, piece)| piece.kind == Kind::Queen);
+    let endgame = !queens || officers <= 2 * (900 + 330);
+
+    let score: i32 = position
+        .pieces()
+        .map(|(square, piece)| {
+            let score = value(piece.kind) + placement(piece.kind, piece.side, square, endgame);
+
+            if piece.side == Side::White {
+                score
+            } else {
+                -score
+            }
+        })
+        .sum();
+
+    match position.turn() {
+        Side::White => score,
+        Side::Black => -score,
+    }
+}
+
+/// The bonus for a piece standing on `square`, from the tables below.
+fn placement(kind: Kind, side: Side, square: Square, endgame: bool) -> i32 {
+    let table = match kind {
+        Kind::Pawn => &PAWN,
+        Kind::Knight => &KNIGHT,
+        Kind::Bishop => &BISHOP,
+        Kind::Rook => &ROOK,
+        Kind::Queen => &QUEEN,
+        Kind::King if endgame => &KING_ENDGAME,
+        Kind::King => &KING,
+    };
+
+    // the tables are drawn from white's side, with the 8th rank on top
+    let (file, rank) = (square % 8, square / 8);
+    let row = match side {
+        Side::White => 7 - rank,
+        Side::Black => rank,
+    };
+
+    table[row * 8 + file]
+}
+
+#[rustfmt::skip]
+const PAWN: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const KNIGHT: [i32; 64] = [
+   -50,-40,-30,-30,-30,-30,-40,-50,
+   -40,-20,  0,  0,  0,  0,-20,-40,
+   -30,  0, 10, 15, 15, 10,  0,-30,
+   -30,  5, 15, 20, 20, 15,  5,-30,
+   -30,  0, 15, 20, 20, 15,  0,-30,
+   -30,  5, 10, 15, 15, 10,  5,-30,
+   -40,-20,  0,  5,  5,  0,-20,-40,
+   -50,-40,-30,-30,-30,-30,-40,-50,
+];
+
+#[rustfmt::skip]
+const BISHOP: [i32; 64] = [
+   -20,-10,-10,-10,-10,-10,-10,-20,
+   -10,  0,  0,  0,  0,  0,  0,-10,
+   -10,  0,  5, 10, 10,  5,  0,-10,
+   -10,  5,  5, 10, 10,  5,  5,-10,
+   -10,  0, 10, 10, 10, 10,  0,-10,
+   -10, 10, 10, 10, 10, 10, 10,-10,
+   -10,  5,  0,  0,  0,  0,  5,-10,
+   -20,-10,-10,-10,-10,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const ROOK: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+
+#[rustfmt::skip]
+const QUEEN: [i32; 64] = [
+   -20,-10,-10, -5, -5,-10,-10,-20,
+   -10,  0,  0,  0,  0,  0,  0,-10,
+   -10,  0,  5,  5,  5,  5,  0,-10,
+    -5,  0,  5,  5,  5,  5,  0, -5,
+     0,  0,  5,  5,  5,  5,  0, -5,
+   -10,  5,  5,  5,  5,  5,  0,-10,
+   -10,  0,  5,  0,  0,  0,  0,-10,
+   -20,-10,-10, -5, -5,-10,-10,-20,
+];
+
+#[rustfmt::skip]
+const KING: [i32; 64] = [
+   -30,-40,-40,-50,-50,-40,-40,-30,
+   -30,-40,-40,-50,-50,-40,-40,-30,
+   -30,-40,-40,-50,-50,-40,-40,-30,
+   -30,-40,-40,-50,-50,-40,-40,-30,
+   -20,-30,-30,-40,-40,-30,-30,-20,
+   -10,-20,-20,-20,-20,-20,-20,-10,
+    20, 20,  0,  0,  0,  0, 20, 20,
+    20, 30, 10,  0,  0, 10, 30, 20,
+];
+
+#[rustfmt::skip]
+const KING_ENDGAME: [i32; 64] = [
+   -50,-40,-30,-20,-20,-30,-40,-50,
+   -30,-20,-10,  0,  0,-10,-20,-30,
+   -30,-10, 20, 30, 30, 20,-10,-30,
+   -30,-10, 30, 40, 40, 30,-10,-30,
+   -30,-10, 30, 40, 40, 30,-10,-30,
+   -30,-10, 20, 30, 30, 20,-10,-30,
+   -30,-30,  0,  0,  0,  0,-30,-30,
+   -50,-30,-30,-30,-30,-30,-30,-50,
+];
@@ -0,0 +1,122 @@
+//! Multiplexes the input of the local player with the messages of the
+//! opponent, so waiting for one never blocks the other.
+//!
+//! Standard input is read by a single thread for the whole process, which
+//! hands every line to the `Events` created last. Once that thread runs, all
+//! input has to be read through `Events`.
+
+use std::{
+    io::{self, BufRead},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::protocol::{Message, Reader};
+
+pub enum Event {
+    /// A line typed by the local player.
+    Input(String),
+    /// A message from the opponent, or the failure of the connection.
+    Message(io::Result<Message>),
+}
+
+/// Where the stdin thread delivers typed lines.
+static INPUT: Mutex<Option<Sender<Event>>> = Mutex::new(None);
+
+/// Set once standard input is closed, after which no more lines come.
+static CLOSED: AtomicBool = AtomicBool::new(false);
+
+pub struct Events {
+    receiver: Receiver<Event>,
+}
+
+impl Events {
+    /// Starts listening to standard input and, if given, to `reader`.
+    pub fn new(reader: Option<Reader>) -> Events {
+        let (sender, receiver) = mpsc::channel();
+
+        if let Some(mut reader) = reader {
+            let sender = sender.clone();
+
+            thread::spawn(move || loop {
+                let message = reader.receive();
+                let closed = message.is_err();
+
+                if sender.send(Event::Message(message)).is_err() || closed {
+                    break;
+                }
+            });
+        }
+
+        *INPUT.lock().unwrap() = Some(sender);
+
+        static STDIN: Once = Once::new();
+        STDIN.call_once(|| {
+            thread::spawn(read_stdin);
+        });
+
+        Events { receiver }
+    }
+
+    /// Waits for the next event, or until `timeout` has passed.
+    pub fn next(&self, timeout: Option<Duration>) -> Option<Event> {
+        match timeout {
+            Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => unreachable!("INPUT holds a sender"),
+            },
+            // INPUT holds a sender, so this never disconnects
+            None => self.receiver.recv().ok(),
+        }
+    }
+
+    /// Waits for the next typed line, dropping the messages that come first.
+    /// Gives `None` once `timeout` has passed, or once standard input is
+    /// closed and the lines read before are used up.
+    pub fn line(&self, timeout: Option<Duration>) -> Option<String> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let timeout = if input_closed() {
+                Some(Duration::ZERO)
+            } else {
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            };
+
+            match self.next(timeout)? {
+                Event::Input(line) => return Some(line),
+                Event::Message(_) => {}
+            }
+        }
+    }
+}
+
+/// Whether standard input is closed, so waiting for more lines is futile.
+pub fn input_closed() -> bool {
+    CLOSED.load(Ordering::SeqCst)
+}
+
+fn read_stdin() {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+
+        if let Some(sender) = &*INPUT.lock().unwrap() {
+            let _ = sender.send(Event::Input(line));
+        }
+    }
+
+    CLOSED.store(true, Ordering::SeqCst);
+
+    // closing stdin leaves the game, like typing quit would
+    if let Some(sender) = &*INPUT.lock().unwrap() {
+        let _ = sender.send(Event::Input("quit".to_string()));
+    }
+}
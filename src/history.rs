@@ -0,0 +1,358 @@
+//! The positions a game went through, kept alongside its `Board` to take
+//! moves back and to apply the draw rules that depend on what came before.
+
+use chess_lib::chess::{Board, Error};
+
+use crate::{
+    position::{Move, Position},
+    Side,
+};
+
+/// A draw either player may claim on their turn.
+const THREEFOLD: usize = 3;
+const FIFTY_MOVES: u32 = 100;
+
+/// A draw the game ends in without anyone asking.
+const FIVEFOLD: usize = 5;
+const SEVENTY_FIVE_MOVES: u32 = 150;
+
+#[derive(Debug, Clone)]
+pub struct History {
+    /// The FEN of every position before the current one.
+    fens: Vec<String>,
+    /// Every move played, in the notation `move_piece` takes.
+    moves: Vec<String>,
+    /// The moves undone, the next one to redo last.
+    undone: Vec<String>,
+    /// The repetition hash of every position, the current one last.
+    hashes: Vec<u64>,
+    /// Half-moves since the last capture or pawn move.
+    halfmove: u32,
+    /// The value of `halfmove` in every position before the current one.
+    halfmoves: Vec<u32>,
+}
+
+impl History {
+    /// A history starting at the position on `board`.
+    pub fn new(board: &Board) -> History {
+        let position = Position::of(board);
+
+        History {
+            fens: Vec::new(),
+            moves: Vec::new(),
+            undone: Vec::new(),
+            hashes: vec![position.repetition_hash()],
+            halfmove: position.halfmove,
+            halfmoves: Vec::new(),
+        }
+    }
+
+    /// Records the move `mv` that took the game from the FEN `before` to the
+    /// position on `board`.
+    pub fn push(&mut self, before: String, mv: &str, board: &Board) {
+        // playing the move that was undone keeps the rest to redo
+        if self.undone.last().map(String::as_str) == Some(mv) {
+            self.undone.pop();
+        } else {
+            self.undone.clear();
+        }
+
+        self.record(before, mv, board);
+    }
+
+    fn record(&mut self, before: String, mv: &str, board: &Board) {
+        let after = Position::of(board);
+
+        // only captures and pawn moves change the pawns or the number of
+        // pieces, promotions included
+        let reset = before.parse::<Position>().map_or(true, |before| {
+            before.pieces().count() != after.pieces().count() || before.pawns().ne(after.pawns())
+        });
+
+        self.fens.push(before);
+        self.moves.push(mv.to_string());
+        self.hashes.push(after.repetition_hash());
+        self.halfmoves.push(self.halfmove);
+        self.halfmove = if reset { 0 } else { self.halfmove + 1 };
+    }
+
+    /// The number of moves played.
+    pub fn len(&self) -> usize {
+        self.fens.len()
+    }
+
+    /// The last move played, if any.
+    pub fn last_move(&self) -> Option<Move> {
+        self.moves.last().and_then(|mv| mv.parse().ok())
+    }
+
+    /// The number of moves that can be redone.
+    pub fn redoable(&self) -> usize {
+        self.undone.len()
+    }
+
+    /// Rewinds `board` by the given number of half-moves, for good.
+    pub fn take_back(&mut self, board: &mut Board, plies: usize) -> Result<(), Error> {
+        let index = self.len().saturating_sub(plies);
+
+        if let Some(fen) = self.fens.get(index) {
+            *board = Board::from_fen(fen)?;
+            self.halfmove = self.halfmoves[index];
+
+            self.fens.truncate(index);
+            self.moves.truncate(index);
+            self.hashes.truncate(index + 1);
+            self.halfmoves.truncate(index);
+        }
+
+        Ok(())
+    }
+
+    /// Takes back the last move on `board`, keeping it to redo. Returns
+    /// whether there was a move to undo.
+    pub fn undo(&mut self, board: &mut Board) -> Result<bool, Error> {
+        let Some(mv) = self.moves.last().cloned() else {
+            return Ok(false);
+        };
+
+        self.take_back(board, 1)?;
+        self.undone.push(mv);
+
+        Ok(true)
+    }
+
+    /// Plays the last move undone on `board` again, and returns it.
+    pub fn redo(&mut self, board: &mut Board) -> Result<Option<String>, Error> {
+        let Some(mv) = self.undone.pop() else {
+            return Ok(None);
+        };
+
+        let before = board.to_fen();
+        board.move_piece(&mv)?;
+        self.record(before, &mv, board);
+
+        Ok(Some(mv))
+    }
+
+    /// The moves played so far in SAN, with their move number and the side
+    /// that played them.
+    pub fn sans(&self) -> Vec<(u32, Side, String)> {
+        let Some(Ok(mut position)) = self.fens.first().map(|fen| fen.parse::<Position>()) else {
+            return Vec::new();
+        };
+
+        let mut sans = Vec::new();
+
+        for mv in &self.moves {
+            let Ok(mv) = mv.parse() else {
+                break;
+            };
+
+            sans.push((position.fullmove, position.turn(), position.san(mv)));
+            position.play(mv);
+        }
+
+        sans
+    }
+
+    /// The moves played so far in SAN, numbered like `1. e4 e5 2. Nf3`.
+    pub fn movetext(&self) -> String {
+        let mut tokens = Vec::new();
+
+        for (number, side, san) in self.sans() {
+            if side == Side::White {
+                tokens.push(format!("{}.", number));
+            } else if tokens.is_empty() {
+                tokens.push(format!("{}...", number));
+            }
+
+            tokens.push(san);
+        }
+
+        tokens.join(" ")
+    }
+
+    /// How often the current position has occurred, itself included.
+    pub fn repetitions(&self) -> usize {
+        let current = self.hashes.last();
+
+        self.hashes
+            .iter()
+            .filter(|&hash| Some(hash) == current)
+            .count()
+    }
+
+    /// The draw the player to move may claim, if any.
+    pub fn claimable_draw(&self) -> Option<&'static str> {
+        if self.repetitions() >= THREEFOLD {
+            Some("threefold repetition")
+        } else if self.halfmove >= FIFTY_MOVES {
+            Some("fifty-move rule")
+        } else {
+            None
+        }
+    }
+
+    /// The draw the game ends in regardless of the players, if any.
+    pub fn automatic_draw(&self) -> Option<&'static str> {
+        if self.repetitions() >= FIVEFOLD {
+            Some("fivefold repetition")
+        } else if self.halfmove >= SEVENTY_FIVE_MOVES {
+            Some("seventy-five-move rule")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::START;
+
+    /// Knights out and back, which brings the start position back.
+    const SHUFFLE: &str = "g1f3 g8f6 f3g1 f6g8";
+
+    /// The game from `fen` through `moves`, in the notation `move_piece`
+    /// takes.
+    fn game(fen: &str, moves: &str) -> (History, Board) {
+        let mut board = Board::from_fen(fen).unwrap();
+        let mut history = History::new(&board);
+        play(&mut history, &mut board, moves);
+
+        (history, board)
+    }
+
+    fn play(history: &mut History, board: &mut Board, moves: &str) {
+        for mv in moves.split_whitespace() {
+            let before = board.to_fen();
+            board.move_piece(mv).unwrap();
+            history.push(before, mv, board);
+        }
+    }
+
+    #[test]
+    fn repetitions_allow_a_claim_then_end_the_game() {
+        let (mut history, mut board) = game(START, SHUFFLE);
+        assert_eq!(history.repetitions(), 2);
+        assert_eq!(history.claimable_draw(), None);
+
+        play(&mut history, &mut board, SHUFFLE);
+        assert_eq!(history.repetitions(), 3);
+        assert_eq!(history.claimable_draw(), Some("threefold repetition"));
+        assert_eq!(history.automatic_draw(), None);
+
+        // leaving the position ends the claim
+        play(&mut history, &mut board, "b1c3");
+        assert_eq!(history.claimable_draw(), None);
+
+        play(&mut history, &mut board, "g8f6 c3b1 f6g8");
+        play(&mut history, &mut board, SHUFFLE);
+        assert_eq!(history.repetitions(), 5);
+        assert_eq!(history.automatic_draw(), Some("fivefold repetition"));
+    }
+
+    #[test]
+    fn en_passant_only_counts_when_it_can_be_played() {
+        let hash = |fen: &str| fen.parse::<Position>().unwrap().repetition_hash();
+
+        // no black pawn can take on e3
+        assert_eq!(
+            hash("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+            hash("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"),
+        );
+
+        // the pawn on d4 can
+        assert_ne!(
+            hash("rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 3"),
+            hash("rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 3"),
+        );
+
+        // the position after the double step comes back without the square
+        let (history, _) = game(START, "e2e4 g8f6 g1f3 f6g8 f3g1");
+        assert_eq!(history.repetitions(), 2);
+    }
+
+    #[test]
+    fn captures_and_pawn_moves_reset_the_fifty_move_count() {
+        let fen = "4k3/4p3/8/3p4/8/8/4P3/4K1N1 w - - 98 60";
+
+        let (history, _) = game(fen, "g1f3");
+        assert_eq!(history.claimable_draw(), None);
+
+        let (history, _) = game(fen, "g1f3 e8d8");
+        assert_eq!(history.claimable_draw(), Some("fifty-move rule"));
+
+        let (history, _) = game(fen, "g1f3 e7e6");
+        assert_eq!(history.halfmove, 0);
+        assert_eq!(history.claimable_draw(), None);
+
+        let (history, _) = game(fen, "g1f3 e8d8 f3d4 d8c8 d4b5 c8d7 b5c3 d7d6 c3d5");
+        assert_eq!(history.halfmove, 0);
+    }
+
+    #[test]
+    fn the_seventy_five_move_rule_ends_the_game() {
+        let fen = "4k3/8/8/8/8/8/8/4K1N1 w - - 148 100";
+
+        let (history, _) = game(fen, "g1f3");
+        assert_eq!(history.automatic_draw(), None);
+
+        let (history, _) = game(fen, "g1f3 e8d8");
+        assert_eq!(history.automatic_draw(), Some("seventy-five-move rule"));
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_game() {
+        let (mut history, mut board) = game(START, "e2e4 e7e5 g1f3 b8c6");
+        let (hashes, fen) = (history.hashes.clone(), board.to_fen());
+
+        assert!(history.undo(&mut board).unwrap());
+        assert!(history.undo(&mut board).unwrap());
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.redoable(), 2);
+        assert_eq!(history.last_move(), "e7e5".parse().ok());
+
+        assert_eq!(history.redo(&mut board).unwrap().as_deref(), Some("g1f3"));
+        assert_eq!(history.redo(&mut board).unwrap().as_deref(), Some("b8c6"));
+        assert_eq!(history.redo(&mut board).unwrap(), None);
+        assert_eq!(history.hashes, hashes);
+        assert_eq!(board.to_fen(), fen);
+
+        // playing the undone move again keeps the rest to redo, another
+        // move drops them
+        history.undo(&mut board).unwrap();
+        history.undo(&mut board).unwrap();
+        play(&mut history, &mut board, "g1f3");
+        assert_eq!(history.redoable(), 1);
+
+        history.undo(&mut board).unwrap();
+        play(&mut history, &mut board, "f1c4");
+        assert_eq!(history.redoable(), 0);
+    }
+
+    #[test]
+    fn take_back_restores_the_fifty_move_count() {
+        let (mut history, mut board) = game(START, "g1f3 g8f6 e2e4");
+        assert_eq!(history.halfmove, 0);
+
+        history.take_back(&mut board, 1).unwrap();
+        assert_eq!(history.halfmove, 2);
+        assert_eq!(history.movetext(), "1. Nf3 Nf6");
+
+        history.take_back(&mut board, 2).unwrap();
+        assert_eq!(history.len(), 0);
+        assert_eq!(board.to_fen(), START);
+        assert_eq!(history.redoable(), 0);
+    }
+
+    #[test]
+    fn movetext_numbers_the_moves() {
+        let (history, _) = game(START, "e2e4 e7e5 g1f3");
+        assert_eq!(history.movetext(), "1. e4 e5 2. Nf3");
+
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let (history, _) = game(fen, "e7e5 g1f3");
+        assert_eq!(history.movetext(), "1... e5 2. Nf3");
+    }
+}
@@ -0,0 +1,621 @@
+//! Headless lobby server for games between two remote players.
+//!
+//! Players connect with `--multiplayer host:port` like they would to a host.
+//! Players sending the same join code are paired with each other, players
+//! without a code are paired first-come, first-served. Every game runs on
+//! its own thread with its own `Board`, the lobby validates each move before
+//! relaying it to the opponent, so to both players it looks like any other
+//! peer.
+//!
+//! A player that loses the connection keeps their seat for a grace period,
+//! and can take it again with `--resume <game> --token <token>`, the token
+//! handed out with their seat.
+//!
+//! Spectators connect with `--watch <game>` and receive every move relayed
+//! in that game, but the lobby never reads anything from them.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chess_lib::chess::{Board, Error};
+
+use crate::{
+    clock::TimeControl,
+    history::History,
+    pgn::{self, Record},
+    position::Position,
+    protocol::{
+        self, ColorPreference, Connection, Greeting, Guest, Message, Offer, Reader, Writer,
+    },
+    Outcome, Side,
+};
+
+/// A player that completed the handshake.
+struct Player {
+    connection: Connection,
+    guest: Guest,
+    /// The secret to resume the game with, known only to the player.
+    token: u64,
+}
+
+#[derive(Default)]
+struct Lobby {
+    /// Players waiting for an opponent, keyed by join code.
+    waiting: HashMap<Option<String>, Player>,
+    /// Games in progress, keyed by game id.
+    games: HashMap<u64, Sender<Event>>,
+    last_game: u64,
+}
+
+/// Everything a game thread reacts to.
+enum Event {
+    /// A message from, or the failed connection of, one of the players.
+    Message(Side, io::Result<Message>),
+    /// A player that lost the connection, and wants to take the seat with
+    /// the given token again.
+    Resume(Connection, u64),
+    /// A new spectator that wants to follow the game.
+    Spectator(Connection),
+}
+
+/// How long a game waits for a player that lost the connection to return.
+const GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+pub fn run(port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    println!("Lobby started on port {}", port);
+
+    let lobby = Arc::new(Mutex::new(Lobby::default()));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let lobby = Arc::clone(&lobby);
+        thread::spawn(move || {
+            if let Err(e) = admit(&lobby, stream) {
+                println!("Rejected connection: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Handshakes with a new connection and pairs it with a waiting player, or
+/// parks it until an opponent with the same join code shows up.
+fn admit(lobby: &Mutex<Lobby>, stream: TcpStream) -> io::Result<()> {
+    let mut connection = Connection::new(stream)?;
+
+    let guest = match protocol::greet(&mut connection)? {
+        Greeting::Play(guest) => guest,
+        Greeting::Resume { game, token } => {
+            return hand_over(lobby, connection, game, |connection| {
+                Event::Resume(connection, token)
+            })
+        }
+        Greeting::Spectate(game) => return hand_over(lobby, connection, game, Event::Spectator),
+    };
+    let mut player = Player {
+        connection,
+        guest,
+        token: rand::random(),
+    };
+
+    loop {
+        let (id, mut opponent) = {
+            let mut lobby = lobby.lock().unwrap();
+
+            match lobby.waiting.remove(&player.guest.code) {
+                Some(opponent) => {
+                    lobby.last_game += 1;
+                    (lobby.last_game, opponent)
+                }
+                None => {
+                    println!("{} is waiting for an opponent", player.guest.name);
+                    lobby.waiting.insert(player.guest.code.clone(), player);
+                    return Ok(());
+                }
+            }
+        };
+
+        // the player that waited longest gets to pick the time control and
+        // the starting position
+        let side = ColorPreference::negotiate(opponent.guest.color, player.guest.color);
+        let time = opponent.guest.time.or(player.guest.time);
+        let start = protocol::pick_start(&opponent.guest.start, &player.guest.start);
+
+        // the waiting player may have given up in the meantime, in which case
+        // the new player takes their place in the queue. Writing to a closed
+        // connection tends to succeed at first, so that is checked before.
+        if opponent.connection.closed() {
+            println!("{} left the lobby", opponent.guest.name);
+            continue;
+        }
+        if let Err(e) = welcome(&mut opponent, side, id, time, &start, &player.guest.name) {
+            println!("{} left the lobby: {}", opponent.guest.name, e);
+            continue;
+        }
+        welcome(
+            &mut player,
+            side.opponent(),
+            id,
+            time,
+            &start,
+            &opponent.guest.name,
+        )?;
+
+        let (white, black) = match side {
+            Side::White => (opponent, player),
+            Side::Black => (player, opponent),
+        };
+
+        println!(
+            "Game {}: {} (white) vs {} (black) started",
+            id, white.guest.name, black.guest.name
+        );
+
+        let result = Game::new(id, time, &start, white, black).and_then(|game| {
+            let events = game.sender.clone();
+            lobby.lock().unwrap().games.insert(id, events);
+
+            game.play()
+        });
+
+        lobby.lock().unwrap().games.remove(&id);
+
+        match result {
+            Ok(outcome) => println!("Game {} finished: {}", id, outcome),
+            Err(e) => println!("Game {} aborted: {}", id, e),
+        }
+
+        return Ok(());
+    }
+}
+
+fn welcome(
+    player: &mut Player,
+    side: Side,
+    id: u64,
+    time: Option<TimeControl>,
+    start: &str,
+    opponent: &str,
+) -> io::Result<()> {
+    player.connection.send(&Message::Welcome {
+        version: protocol::VERSION,
+        color: side,
+        game: id,
+        time,
+        token: Some(player.token),
+        name: opponent.to_string(),
+    })?;
+    player
+        .connection
+        .send(&Message::Position(start.to_string()))
+}
+
+/// Hands a spectator or a returning player over to the thread of the game
+/// with id `id`.
+fn hand_over(
+    lobby: &Mutex<Lobby>,
+    mut connection: Connection,
+    id: u64,
+    event: impl FnOnce(Connection) -> Event,
+) -> io::Result<()> {
+    let game = lobby.lock().unwrap().games.get(&id).cloned();
+
+    let Some(game) = game else {
+        let reason = format!("there is no game with id {}", id);
+        connection.send(&Message::Reject(reason.clone()))?;
+        return Err(io::Error::new(io::ErrorKind::NotFound, reason));
+    };
+
+    // the game may have finished in the meantime, which drops the connection
+    let _ = game.send(event(connection));
+
+    Ok(())
+}
+
+/// A game in progress between two remote players.
+struct Game {
+    id: u64,
+    time: Option<TimeControl>,
+    /// The FEN of the position the game started from.
+    start: String,
+    board: Board,
+    /// The positions played through, to take moves back and tell draws.
+    history: History,
+    /// Every move played, to replay them for a returning player.
+    moves: Vec<String>,
+    record: Record,
+    /// The pending offer, and the side that made it.
+    offer: Option<(Side, Offer)>,
+    /// The time left for white and black, as last reported by the players.
+    clock: Option<(Duration, Duration)>,
+    /// The sending halves of both connections, indexed by `Side`. `None`
+    /// while a player has lost the connection.
+    writers: [Option<Writer>; 2],
+    /// When the players lost their connection, indexed by `Side`.
+    left: [Option<Instant>; 2],
+    /// The tokens the players resume their seats with, indexed by `Side`.
+    tokens: [u64; 2],
+    names: [String; 2],
+    spectators: Vec<Writer>,
+    sender: Sender<Event>,
+    events: mpsc::Receiver<Event>,
+}
+
+impl Game {
+    fn new(
+        id: u64,
+        time: Option<TimeControl>,
+        start: &str,
+        white: Player,
+        black: Player,
+    ) -> Result<Game, Error> {
+        let (sender, events) = mpsc::channel();
+
+        let (white_reader, white_writer) = white.connection.split();
+        let (black_reader, black_writer) = black.connection.split();
+
+        forward(white_reader, Side::White, sender.clone());
+        forward(black_reader, Side::Black, sender.clone());
+
+        let board = Board::from_fen(start)?;
+        let history = History::new(&board);
+        let event = format!("Lobby game {}", id);
+        let record = Record::new(&event, &white.guest.name, &black.guest.name, &board);
+
+        Ok(Game {
+            id,
+            time,
+            start: start.to_string(),
+            board,
+            history,
+            moves: Vec::new(),
+            record,
+            offer: None,
+            clock: None,
+            writers: [Some(white_writer), Some(black_writer)],
+            left: [None, None],
+            tokens: [white.token, black.token],
+            names: [white.guest.name, black.guest.name],
+            spectators: Vec::new(),
+            sender,
+            events,
+        })
+    }
+
+    fn play(mut self) -> Result<Outcome, Error> {
+        let result = self.relay();
+
+        if let Ok(outcome) = &result {
+            let over = Message::GameOver(outcome.clone());
+            self.send(Side::White, &over);
+            self.send(Side::Black, &over);
+            self.broadcast(&over);
+
+            self.record.result = Some(outcome.clone());
+            match self.record.archive() {
+                Ok(_) => println!("Game {}: saved to {}", self.id, pgn::ARCHIVE),
+                Err(e) => println!("Game {}: could not save: {}", self.id, e),
+            }
+        }
+
+        for writer in self.writers.iter().flatten().chain(&self.spectators) {
+            writer.close();
+        }
+
+        result
+    }
+
+    fn relay(&mut self) -> Result<Outcome, Error> {
+        loop {
+            let event = match self.deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+
+                    match self.events.recv_timeout(timeout) {
+                        Ok(event) => event,
+                        Err(_) => return Ok(self.abandoned()),
+                    }
+                }
+                // the game holds a sender itself, so this never disconnects
+                None => self.events.recv().unwrap(),
+            };
+
+            let (side, message) = match event {
+                Event::Message(side, message) => (side, message),
+                Event::Resume(connection, token) => {
+                    self.resume(connection, token);
+                    continue;
+                }
+                Event::Spectator(connection) => {
+                    self.add_spectator(connection);
+                    continue;
+                }
+            };
+
+            match message {
+                Ok(message) => {
+                    if let Some(outcome) = self.handle(side, message)? {
+                        return Ok(outcome);
+                    }
+                }
+                Err(e) => {
+                    println!("Game {}: {} disconnected: {}", self.id, self.name(side), e);
+                    self.vacate(side);
+                }
+            }
+        }
+    }
+
+    /// Validates and relays a message of the player on `side`, returning the
+    /// outcome when it ends the game.
+    fn handle(&mut self, side: Side, message: Message) -> Result<Option<Outcome>, Error> {
+        match message {
+            Message::Move(mv) if side == Side::to_move(&self.board) => {
+                let before = self.board.to_fen();
+
+                let played = crate::check_promotion(&self.board, &mv)
+                    .and_then(|_| self.board.move_piece(&mv).map_err(|e| e.to_string()));
+
+                match played {
+                    Ok(_) => {
+                        self.history.push(before, &mv, &self.board);
+                        self.moves.push(mv.clone());
+
+                        // the mover reports their clock right before the move
+                        let left = self.clock.map(|(white, black)| match side {
+                            Side::White => white,
+                            Side::Black => black,
+                        });
+                        self.record.push(&mv, left);
+
+                        // a move declines the takeback it crossed
+                        if let Some((offered_by, _)) = self.offer.take() {
+                            self.send(offered_by, &Message::Decline);
+                        }
+
+                        let checksum = protocol::checksum(&self.board);
+                        self.send(side, &Message::MoveAccepted(checksum));
+                        self.send(side.opponent(), &Message::Move(mv.clone()));
+                        self.broadcast(&Message::Move(mv));
+
+                        let position = Position::of(&self.board);
+                        if let Some(outcome) = Outcome::after(&position, &self.history) {
+                            return Ok(Some(outcome));
+                        }
+                    }
+                    Err(e) => self.send(side, &Message::MoveRejected(e)),
+                }
+            }
+            Message::Move(_) => {
+                let reason = "it is not your turn".to_string();
+                self.send(side, &Message::MoveRejected(reason));
+            }
+            Message::MoveAccepted(checksum) if checksum == protocol::checksum(&self.board) => {}
+            Message::Clock { white, black } if side == Side::to_move(&self.board) => {
+                self.clock = Some((white, black));
+
+                let clock = Message::Clock { white, black };
+                self.send(side.opponent(), &clock);
+                self.broadcast(&clock);
+
+                let left = match side {
+                    Side::White => white,
+                    Side::Black => black,
+                };
+
+                if left.is_zero() {
+                    return Ok(Some(Outcome::out_of_time(side)));
+                }
+            }
+            Message::Chat { side: from, text } if from == side => {
+                let chat = Message::Chat { side, text };
+                self.send(side.opponent(), &chat);
+                self.broadcast(&chat);
+            }
+            // a claimed draw ends the game once the lobby agrees it is due
+            Message::GameOver(Outcome::Draw(reason))
+                if side == Side::to_move(&self.board)
+                    && self.history.claimable_draw() == Some(reason.as_str()) =>
+            {
+                return Ok(Some(Outcome::Draw(reason)));
+            }
+            Message::Resign(resigned) if resigned == side => {
+                self.send(side.opponent(), &Message::Resign(side));
+                return Ok(Some(Outcome::resignation(side)));
+            }
+            Message::Offer(offer) if self.offer.is_none() => {
+                self.offer = Some((side, offer));
+                self.send(side.opponent(), &Message::Offer(offer));
+            }
+            // the offer of the opponent came first
+            Message::Offer(_) => self.send(side, &Message::Decline),
+            reply @ (Message::Accept | Message::Decline) => match self.offer.take() {
+                Some((offered_by, offer)) if offered_by != side => {
+                    self.send(offered_by, &reply);
+
+                    if reply == Message::Accept {
+                        match offer {
+                            Offer::Draw => {
+                                return Ok(Some(Outcome::Draw("draw by agreement".to_string())))
+                            }
+                            Offer::Takeback(plies) => {
+                                self.history.take_back(&mut self.board, plies)?;
+                                self.moves.truncate(self.history.len());
+                                self.record.truncate(self.history.len());
+                                self.broadcast(&Message::Position(self.board.to_fen()));
+                            }
+                        }
+                    }
+                }
+                // the offer was declined already by the move it crossed
+                None => {}
+                Some(_) => return Err(protocol::unexpected(&reply).into()),
+            },
+            Message::MoveAccepted(_) | Message::MoveRejected(_) => {
+                let error = format!("the board of {} no longer matches", self.name(side));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error).into());
+            }
+            other => return Err(protocol::unexpected(&other).into()),
+        }
+
+        Ok(None)
+    }
+
+    /// Sends `message` to the player on `side`, if connected. A failed
+    /// connection is noticed by its reader thread.
+    fn send(&mut self, side: Side, message: &Message) {
+        if let Some(writer) = &mut self.writers[side as usize] {
+            let _ = writer.send(message);
+        }
+    }
+
+    /// Sends `message` to every spectator, dropping the ones that left.
+    fn broadcast(&mut self, message: &Message) {
+        self.spectators
+            .retain_mut(|spectator| spectator.send(message).is_ok());
+    }
+
+    /// Frees the seat of a player that lost the connection, so they can
+    /// resume the game within the grace period.
+    fn vacate(&mut self, side: Side) {
+        if let Some(writer) = self.writers[side as usize].take() {
+            writer.close();
+        }
+
+        self.left[side as usize] = Some(Instant::now());
+
+        // an offer either way can no longer be settled, the returning player
+        // starts without one
+        if let Some((offered_by, _)) = self.offer.take() {
+            if offered_by == side {
+                self.send(side.opponent(), &Message::Withdraw);
+            } else {
+                self.send(offered_by, &Message::Decline);
+            }
+        }
+    }
+
+    /// When the first player that left forfeits the game.
+    fn deadline(&self) -> Option<Instant> {
+        self.left
+            .iter()
+            .flatten()
+            .min()
+            .map(|&left| left + GRACE_PERIOD)
+    }
+
+    fn abandoned(&self) -> Outcome {
+        match self.left {
+            [Some(_), Some(_)] => Outcome::Draw("both players abandoned the game".to_string()),
+            [Some(_), None] => Outcome::Win(Side::Black, "white abandoned the game".to_string()),
+            _ => Outcome::Win(Side::White, "black abandoned the game".to_string()),
+        }
+    }
+
+    /// Seats a returning player and replays the game so far to them.
+    fn resume(&mut self, mut connection: Connection, token: u64) {
+        // only the player who left knows the token of their seat
+        let side = [Side::White, Side::Black].into_iter().find(|&side| {
+            self.writers[side as usize].is_none() && self.tokens[side as usize] == token
+        });
+
+        let Some(side) = side else {
+            let reason = format!("no vacated seat in game {} has that token", self.id);
+            let _ = connection.send(&Message::Reject(reason));
+            return;
+        };
+
+        let welcome = Message::Welcome {
+            version: protocol::VERSION,
+            color: side,
+            game: self.id,
+            time: self.time,
+            token: Some(token),
+            name: self.name(side.opponent()).to_string(),
+        };
+
+        let mut sent = connection
+            .send(&welcome)
+            .and_then(|_| connection.send(&Message::Position(self.start.clone())))
+            .and_then(|_| connection.send(&Message::Moves(self.moves.clone())));
+
+        if let Some(time) = self.time {
+            let (white, black) = self.clock.unwrap_or((time.base, time.base));
+            sent = sent.and_then(|_| connection.send(&Message::Clock { white, black }));
+        }
+
+        if sent.is_err() {
+            return;
+        }
+
+        let (reader, writer) = connection.split();
+        forward(reader, side, self.sender.clone());
+
+        self.writers[side as usize] = Some(writer);
+        self.left[side as usize] = None;
+
+        println!(
+            "Game {}: {} resumed playing {}",
+            self.id,
+            self.name(side),
+            side
+        );
+    }
+
+    fn add_spectator(&mut self, connection: Connection) {
+        let (_, mut writer) = connection.split();
+
+        let players = Message::Players {
+            white: self.names[0].clone(),
+            black: self.names[1].clone(),
+        };
+        let position = Message::Position(self.board.to_fen());
+
+        if writer.send(&players).is_err() || writer.send(&position).is_err() {
+            return;
+        }
+
+        if let Some((white, black)) = self.clock {
+            if writer.send(&Message::Clock { white, black }).is_err() {
+                return;
+            }
+        }
+
+        self.spectators.push(writer);
+    }
+
+    fn name(&self, side: Side) -> &str {
+        &self.names[side as usize]
+    }
+}
+
+/// Forwards every message received on `reader` to `events`, tagged with the
+/// side of the player, until the connection fails.
+fn forward(mut reader: Reader, side: Side, events: Sender<Event>) {
+    thread::spawn(move || loop {
+        let message = reader.receive();
+        let closed = message.is_err();
+
+        if events.send(Event::Message(side, message)).is_err() || closed {
+            break;
+        }
+    });
+}
@@ -1,11 +1,129 @@
-use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-};
+mod clock;
+mod engine;
+mod events;
+mod history;
+mod lobby;
+mod network;
+mod pgn;
+mod position;
+mod protocol;
+mod uci;
+mod viewer;
+mod xboard;
+
+use std::{io::Write, path::PathBuf, sync::atomic::AtomicBool, time::Duration};
 
 use chess_lib::chess::{Board, Error};
 use clap::*;
+use clock::{Clock, TimeControl};
 use colored::*;
+use engine::{Limits, Score, LEVELS};
+use events::Events;
+use history::History;
+use pgn::Record;
+use position::{Kind, Move, Position, Square};
+use protocol::ColorPreference;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Side {
+    White,
+    Black,
+}
+
+impl Side {
+    /// The side whose turn it is on `board`.
+    pub fn to_move(board: &Board) -> Side {
+        if board.turn() == chess_lib::chess::Color::White {
+            Side::White
+        } else {
+            Side::Black
+        }
+    }
+
+    pub fn opponent(self) -> Side {
+        match self {
+            Side::White => Side::Black,
+            Side::Black => Side::White,
+        }
+    }
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// How a game ended, along with a description of why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Win(Side, String),
+    Draw(String),
+}
+
+impl Outcome {
+    /// The result in PGN notation, e.g. `1-0`.
+    pub fn score(&self) -> &'static str {
+        match self {
+            Outcome::Win(Side::White, _) => "1-0",
+            Outcome::Win(Side::Black, _) => "0-1",
+            Outcome::Draw(_) => "1/2-1/2",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            Outcome::Win(_, reason) | Outcome::Draw(reason) => reason,
+        }
+    }
+
+    pub fn resignation(side: Side) -> Outcome {
+        Outcome::Win(side.opponent(), format!("{} resigned", side))
+    }
+
+    pub fn out_of_time(side: Side) -> Outcome {
+        Outcome::Win(side.opponent(), format!("{} ran out of time", side))
+    }
+
+    /// How the game ended in `position`, if it did.
+    pub fn of(position: &Position) -> Option<Outcome> {
+        let side = position.turn();
+
+        if !position.legal_moves().is_empty() {
+            None
+        } else if position.in_check() {
+            Some(Outcome::Win(
+                side.opponent(),
+                format!("{} is checkmated", side),
+            ))
+        } else {
+            Some(Outcome::Draw("stalemate".to_string()))
+        }
+    }
+
+    /// How the game ended in `position`, reached through `history`, if it
+    /// did. Besides checkmate and stalemate, this covers insufficient
+    /// material and the draws that need no claim.
+    pub fn after(position: &Position, history: &History) -> Option<Outcome> {
+        if let Some(outcome) = Outcome::of(position) {
+            return Some(outcome);
+        }
+
+        let reason = if position.insufficient_material() {
+            "insufficient material"
+        } else {
+            history.automatic_draw()?
+        };
+
+        Some(Outcome::Draw(reason.to_string()))
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}", self.score(), self.reason())
+    }
+}
 
 #[derive(Debug, Clone)]
 enum ServerOrClient {
@@ -36,138 +154,329 @@ where
     }
 }
 
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run as a UCI engine on stdin and stdout, for chess GUIs and tools
+    Uci,
+    /// Run as an engine speaking the XBoard protocol on stdin and stdout
+    Xboard,
+    /// Step through the games of a PGN file
+    View {
+        /// The PGN file to read
+        file: PathBuf,
+    },
+}
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, value_parser = parse_key_val::<String, u16>)]
     multiplayer: Option<ServerOrClient>,
+
+    /// Run a headless lobby server on this port that pairs remote players
+    #[arg(
+        short,
+        long,
+        value_name = "PORT",
+        conflicts_with_all = ["multiplayer", "join", "watch", "resume", "token"]
+    )]
+    lobby: Option<u16>,
+
+    /// Join code used to meet a specific opponent on a lobby server
+    #[arg(
+        short,
+        long,
+        value_name = "CODE",
+        value_parser = parse_code,
+        requires = "multiplayer"
+    )]
+    join: Option<String>,
+
+    /// Follow the game with this id on a lobby server instead of playing
+    #[arg(
+        short,
+        long,
+        value_name = "GAME",
+        conflicts_with = "join",
+        requires = "multiplayer"
+    )]
+    watch: Option<u64>,
+
+    /// Take your seat again in the game with this id on a lobby server
+    #[arg(
+        short,
+        long,
+        value_name = "GAME",
+        conflicts_with_all = ["join", "watch"],
+        requires_all = ["multiplayer", "token"]
+    )]
+    resume: Option<u64>,
+
+    /// The token of your seat, shown when you joined the game
+    #[arg(long, requires = "resume")]
+    token: Option<u64>,
+
+    /// Name shown to your opponent in multiplayer games
+    #[arg(short, long, default_value = "Anonymous")]
+    name: String,
+
+    /// Color you would like to play in multiplayer games
+    #[arg(short, long, value_enum, default_value_t = ColorPreference::Random)]
+    color: ColorPreference,
+
+    /// Time control in minutes, plus an increment (5+3) or a delay (5d3) in
+    /// seconds per move
+    #[arg(short, long, value_name = "MINUTES")]
+    time: Option<TimeControl>,
+
+    /// Play against the computer, as white unless black is given
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SIDE",
+        num_args = 0..=1,
+        default_missing_value = "white",
+        conflicts_with_all = ["multiplayer", "lobby"]
+    )]
+    vs_computer: Option<Side>,
+
+    /// Strength of the computer opponent, from 1 to 10
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=10))]
+    level: u8,
+
+    /// External UCI engine to play against, and to ask for a hint or an eval
+    #[arg(long, value_name = "PATH")]
+    engine: Option<PathBuf>,
+
+    /// Position to start the game from instead of the standard one
+    #[arg(long, value_parser = parse_fen)]
+    fen: Option<String>,
+}
+
+impl Args {
+    /// The FEN of the position to start from.
+    fn start(&self) -> &str {
+        self.fen.as_deref().unwrap_or(position::START)
+    }
+}
+
+/// A join code travels as a single word of `HELLO`, where `-` means none.
+fn parse_code(code: &str) -> Result<String, String> {
+    if code.is_empty() || code == "-" || code.contains(char::is_whitespace) {
+        Err("a join code is a single word, without spaces".to_string())
+    } else {
+        Ok(code.to_string())
+    }
+}
+
+fn parse_fen(fen: &str) -> Result<String, String> {
+    board_from_fen(fen).map(|_| fen.trim().to_string())
+}
+
+/// Sets up the position `fen`, explaining what is wrong with it if it is not
+/// valid.
+pub(crate) fn board_from_fen(fen: &str) -> Result<Board, String> {
+    // chess-lib is not too precise about what is wrong with a FEN
+    fen.trim().parse::<Position>()?;
+
+    Board::from_fen(fen.trim()).map_err(|e| e.to_string())
 }
 
 fn main() -> Result<(), Error> {
     // arguments
     let args = Args::parse();
 
+    match args.command {
+        Some(Command::Uci) => return uci::run(),
+        Some(Command::Xboard) => return xboard::run(computer(&args)?),
+        Some(Command::View { file }) => return viewer::run(&file),
+        None => {}
+    }
+
+    if let Some(port) = args.lobby {
+        return lobby::run(port);
+    }
+
+    // a port to host on parses like any other --multiplayer value
+    let client_only = args.join.is_some() || args.watch.is_some() || args.resume.is_some();
+    if client_only && matches!(args.multiplayer, Some(ServerOrClient::Server(_))) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--join, --watch and --resume need --multiplayer host:port, not a port to host on",
+            )
+            .exit();
+    }
+
     match args.multiplayer {
         Some(ServerOrClient::Server(port)) => {
-            server(port)?;
-        }
-        Some(ServerOrClient::Client(host, port)) => {
-            client(host, port)?;
+            network::server(port, &args)?;
         }
-        _ => singleplayer()?,
+        Some(ServerOrClient::Client(ref host, port)) => match args.watch {
+            Some(game) => network::spectator(host, port, game, args.color)?,
+            None => network::client(host, port, &args)?,
+        },
+        _ => match computer(&args) {
+            Ok(computer) => singleplayer(
+                args.time,
+                computer,
+                args.vs_computer.map(Side::opponent),
+                &args.name,
+                args.start(),
+            )?,
+            Err(e) => println!("{}", e.to_string().red()),
+        },
     }
 
     Ok(())
 }
 
-fn client(host: String, port: u16) -> Result<(), Error> {
-    let mut board = Board::default_board()?;
-    let mut error: Option<String> = None;
+/// The engine asked for on the command line, the built-in one by default.
+fn computer(args: &Args) -> std::io::Result<Computer> {
+    match &args.engine {
+        Some(path) => match uci::External::spawn(path) {
+            Ok(engine) => Ok(Computer::External(engine)),
+            Err(e) => {
+                let message = format!("Could not start {}: {}", path.display(), e);
+                Err(std::io::Error::new(e.kind(), message))
+            }
+        },
+        None => Ok(Computer::BuiltIn(args.level)),
+    }
+}
 
-    let mut stream = TcpStream::connect(format!("{}:{}", host, port))?;
+/// The width of every line of `move_panel`.
+const MOVES_WIDTH: usize = 21;
 
-    loop {
-        // clear screen
-        print!("{}[2J", 27 as char);
+/// Lines printed around the board and its panel: the title, the rejoin hint
+/// of lobby games, clock, turn, notices and the prompt.
+const RESERVED_LINES: usize = 10;
 
-        if error.is_some() {
-            println!("\n{}\n", error.clone().unwrap().red());
+/// The moves of `history` in two numbered columns, the latest ones when
+/// they do not all fit on the terminal.
+fn move_panel(history: &History) -> Vec<String> {
+    let mut rows: Vec<(u32, String, String)> = Vec::new();
+
+    for (number, side, san) in history.sans() {
+        match (side, rows.last_mut()) {
+            (Side::Black, Some((last, _, black))) if *last == number => *black = san,
+            (Side::White, _) => rows.push((number, san, String::new())),
+            (Side::Black, _) => rows.push((number, "...".to_string(), san)),
         }
+    }
 
-        // print board
-        draw_for_black(&board);
+    let height = terminal_size::terminal_size()
+        .map_or(24, |(_, terminal_size::Height(height))| usize::from(height));
 
-        let mut input = String::new();
+    // at least as long as the board is high, with one line for the title
+    let skip = rows
+        .len()
+        .saturating_sub(height.saturating_sub(RESERVED_LINES).max(10) - 1);
 
-        if board.turn() == chess_lib::chess::Color::White {
-            println!("\n{} to move:", board.turn().to_string().bold());
-            let mut buffer = [0; 4];
-            stream.read_exact(&mut buffer)?;
-            input = String::from_utf8_lossy(&buffer).to_string();
-        } else {
-            // print turn
-            println!("\n{} to move:", board.turn().to_string().bold());
-            print!("> ");
+    let title = format!("{:5}{:<8}{:<8}", "", "White", "Black");
 
-            // flush stdout
-            std::io::stdout().flush().unwrap();
+    std::iter::once(title.bold().to_string())
+        .chain(
+            rows.iter()
+                .skip(skip)
+                .map(|(number, white, black)| format!("{:>3}. {:<8}{:<8}", number, white, black)),
+        )
+        .collect()
+}
 
-            // a move consists of 4 characters (e.g. e2e4)
-            std::io::stdin().read_line(&mut input).unwrap();
-            input = input.trim().to_string();
+/// Puts the lines of `move_panel` and `right` side by side.
+fn with_moves(moves: Vec<String>, right: Vec<String>) -> Vec<String> {
+    let lines = moves.len().max(right.len());
+    let mut moves = moves.into_iter();
+    let mut right = right.into_iter();
 
-            // send move to server
-            stream.write_all(input.as_bytes())?;
-        }
+    (0..lines)
+        .map(|_| {
+            let left = moves.next().unwrap_or_else(|| " ".repeat(MOVES_WIDTH));
 
-        error = match board.move_piece(&input) {
-            Ok(_) => None,
-            Err(e) => Some(e.to_string()),
-        }
-    }
+            match right.next() {
+                Some(line) => format!("{}  {}", left, line),
+                None => left,
+            }
+        })
+        .collect()
 }
 
-fn server(port: u16) -> Result<(), Error> {
-    let server = TcpListener::bind(format!("0.0.0.0:{}", port))?;
-    println!("Server started on port {}", port);
+/// The last few chat messages, to be drawn beside the board.
+fn chat_panel(chat: &[String]) -> Vec<String> {
+    // one line for the title, the board is ten lines high
+    let skip = chat.len().saturating_sub(9);
 
-    for stream in server.incoming() {
-        let mut stream = stream?;
-        let mut board = Board::default_board()?;
+    std::iter::once("Chat".bold().to_string())
+        .chain(
+            chat.iter()
+                .skip(skip)
+                .map(|line| line.chars().take(40).collect()),
+        )
+        .collect()
+}
 
-        let mut error: Option<String> = None;
+/// Plays games from the position `start` on this terminal, with `computer`
+/// playing the side `plays` if given and `name` the other one, until the
+/// player has had enough.
+fn singleplayer(
+    time: Option<TimeControl>,
+    mut computer: Computer,
+    plays: Option<Side>,
+    name: &str,
+    start: &str,
+) -> Result<(), Error> {
+    let events = Events::new(None);
 
-        loop {
-            // clear screen
-            print!("{}[2J", 27 as char);
+    loop {
+        let (record, outcome) =
+            singleplayer_game(&events, time, &mut computer, plays, name, start)?;
 
-            if error.is_some() {
-                println!("\n{}\n", error.clone().unwrap().red());
-            }
+        // leaving a game also ends the session
+        if outcome.is_none() || post_game(&record, || events.line(None)) == AfterGame::Quit {
+            return Ok(());
+        }
+    }
+}
 
-            // print board
-            draw_for_white(&board);
+/// Plays a single game, see `singleplayer`. Returns how it ended, unless
+/// the player left before.
+fn singleplayer_game(
+    events: &Events,
+    time: Option<TimeControl>,
+    computer: &mut Computer,
+    plays: Option<Side>,
+    name: &str,
+    start: &str,
+) -> Result<(Record, Option<Outcome>), Error> {
+    let mut board = Board::from_fen(start)?;
 
-            let mut input = String::new();
+    let mut history = History::new(&board);
 
-            // server is white, goes first
-            if board.turn() == chess_lib::chess::Color::White {
-                // print turn
-                println!("\n{} to move:", board.turn().to_string().bold());
-                print!("> ");
+    let (white, black) = match plays {
+        Some(Side::White) => (computer.name(), name.to_string()),
+        Some(Side::Black) => (name.to_string(), computer.name()),
+        None => (name.to_string(), name.to_string()),
+    };
+    let mut record = Record::new("Casual game", &white, &black, &board);
 
-                // flush stdout
-                std::io::stdout().flush().unwrap();
+    let mut error: Option<String> = Option::None;
 
-                // a move consists of 4 characters (e.g. e2e4)
-                std::io::stdin().read_line(&mut input).unwrap();
-                input = input.trim().to_string();
+    // the answer to hint or eval
+    let mut notice: Option<String> = None;
 
-                stream.write_all(input.as_bytes())?;
-            } else {
-                println!("\n{} to move:", board.turn().to_string().bold());
-                // client is black, goes second
-                let mut buffer = [0; 4];
-                stream.read(&mut buffer).unwrap();
-                input = String::from_utf8_lossy(&buffer).to_string();
-            }
+    // the squares asked for with moves, shown once
+    let mut selected: Vec<Square> = Vec::new();
 
-            error = match board.move_piece(&input) {
-                Ok(_) => None,
-                Err(e) => Some(e.to_string()),
-            }
-        }
+    let mut clock = time.map(Clock::new);
+    if let Some(clock) = &mut clock {
+        clock.start(Side::to_move(&board));
     }
 
-    Ok(())
-}
-
-fn singleplayer() -> Result<(), Error> {
-    let mut board = Board::default_board()?;
-
-    let mut error: Option<String> = Option::None;
-
-    loop {
+    let outcome = loop {
         // clear screen
         print!("{}[2J", 27 as char);
 
@@ -175,28 +484,86 @@ fn singleplayer() -> Result<(), Error> {
             println!("\n{}\n", error.clone().unwrap().red());
         }
 
-        // draw a chess board with file and ranks identifiers
-        draw_for_white(&board);
+        // draw a chess board with file and ranks identifiers, from the
+        // perspective of the human player
+        let highlights = Highlights {
+            destinations: std::mem::take(&mut selected),
+            ..Highlights::new(&board, history.last_move())
+        };
+
+        draw(
+            &board,
+            plays.map_or(Side::White, Side::opponent),
+            &move_panel(&history),
+            &highlights,
+        );
+
+        if let Some(clock) = &clock {
+            println!("\n{}", clock);
+        }
+
+        if let Some(outcome) = Outcome::after(&Position::of(&board), &history) {
+            break Some(outcome);
+        }
 
         // print turn
         println!("\n{} to move:", board.turn().to_string().bold());
+
+        if let Some(notice) = notice.take() {
+            println!("{}", notice);
+        }
+
+        if Some(Side::to_move(&board)) == plays {
+            println!("Thinking...");
+
+            let outcome = computer_move(
+                &mut board,
+                &mut history,
+                clock.as_mut(),
+                computer,
+                &mut record,
+            )?;
+
+            if outcome.is_some() {
+                break outcome;
+            }
+
+            continue;
+        }
+
+        if let Some(reason) = history.claimable_draw() {
+            println!("You may claim a draw by {}, type claim", reason);
+        }
+
         print!("> ");
 
         // flush stdout
         std::io::stdout().flush().unwrap();
 
-        // a move consists of 4 characters (e.g. e2e4)
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+        // a move, either e.g. e2e4 or SAN like Nf3, or a command, typed
+        // before the flag falls
+        let timeout = clock
+            .as_ref()
+            .map(|clock| clock.remaining(Side::to_move(&board)));
+        let input = events.line(timeout);
+
+        if let Some(flagged) = clock.as_ref().and_then(Clock::flagged) {
+            break Some(Outcome::out_of_time(flagged));
+        }
+
+        // standard input is closed
+        let Some(input) = input else {
+            break None;
+        };
         let input = input.trim();
 
         let mut cmd = input.split_whitespace().into_iter();
 
         match cmd.next() {
             // Exit commands
-            Some("q") => break,
-            Some("quit") => break,
-            Some("exit") => break,
+            Some("q") => break None,
+            Some("quit") => break None,
+            Some("exit") => break None,
 
             // Save command, takes parameter of file
             Some("save") => {
@@ -207,22 +574,515 @@ fn singleplayer() -> Result<(), Error> {
             Some("load") => {
                 let filename = cmd.next().unwrap_or("game.txt");
                 board.load(filename)?;
+
+                // the moves that led to the loaded position are unknown
+                record = Record::new("Casual game", &white, &black, &board);
+                history = History::new(&board);
+            }
+
+            Some("fen") => notice = Some(board.to_fen()),
+
+            Some("setfen") => {
+                let fen = input["setfen".len()..].trim();
+
+                match board_from_fen(fen) {
+                    Ok(position) => {
+                        board = position;
+
+                        // the game starts over from the new position
+                        record = Record::new("Casual game", &white, &black, &board);
+                        history = History::new(&board);
+                    }
+                    Err(_) if fen.is_empty() => {
+                        error = Some("expected a FEN after setfen".to_string())
+                    }
+                    Err(e) => error = Some(format!("invalid FEN {:?}: {}", fen, e)),
+                }
+            }
+
+            Some("pgn") => {
+                let filename = cmd.next().unwrap_or("game.pgn");
+
+                error = match record.save(filename) {
+                    Ok(_) => {
+                        notice = Some(format!("Saved the game to {}", filename));
+                        None
+                    }
+                    Err(e) => Some(format!("Could not save {}: {}", filename, e)),
+                };
+            }
+
+            Some("claim") => match history.claimable_draw() {
+                Some(reason) => break Some(Outcome::Draw(reason.to_string())),
+                None => error = Some("there is no draw to claim".to_string()),
+            },
+
+            Some("undo") => {
+                if !history.undo(&mut board)? {
+                    error = Some("there is no move to undo".to_string());
+                }
+
+                // against the computer, its reply goes along with the move
+                while Some(Side::to_move(&board)) == plays && history.undo(&mut board)? {}
+
+                record.truncate(history.len());
+                restart(&mut clock, &board);
+            }
+
+            Some("redo") => {
+                let mut redone = 0;
+
+                // the computer's reply comes back along with the move
+                while redone == 0 || Some(Side::to_move(&board)) == plays {
+                    let Some(mv) = history.redo(&mut board)? else {
+                        break;
+                    };
+
+                    record.push(&mv, None);
+                    redone += 1;
+                }
+
+                if redone == 0 {
+                    error = Some("there is no move to redo".to_string());
+                }
+
+                restart(&mut clock, &board);
             }
 
+            // to the position after that many half-moves
+            Some("goto") => {
+                let plies = history.len() + history.redoable();
+
+                match cmd.next().map(str::parse::<usize>) {
+                    Some(Ok(ply)) if ply <= plies => {
+                        while history.len() > ply && history.undo(&mut board)? {}
+
+                        while history.len() < ply {
+                            let Some(mv) = history.redo(&mut board)? else {
+                                break;
+                            };
+
+                            record.push(&mv, None);
+                        }
+
+                        record.truncate(history.len());
+                        restart(&mut clock, &board);
+                    }
+                    Some(Ok(_)) => error = Some(format!("the game has only {} plies", plies)),
+                    _ => error = Some("expected a ply number after goto".to_string()),
+                }
+            }
+
+            Some("moves") => match destinations(&board, cmd.next()) {
+                Ok(squares) => selected = squares,
+                Err(e) => error = Some(e),
+            },
+
+            Some("history") => {
+                notice = Some(match history.movetext() {
+                    moves if moves.is_empty() => "No moves have been played yet".to_string(),
+                    moves => moves,
+                })
+            }
+
+            Some("hint") => match computer.analyse(&board, analysis()) {
+                Ok((Some(mv), _)) => notice = Some(format!("Hint: {}", mv)),
+                Ok((None, _)) => error = Some("there is no move to play".to_string()),
+                Err(e) => error = Some(e.to_string()),
+            },
+
+            Some("eval") => match computer.analyse(&board, analysis()) {
+                Ok((_, Some(score))) => {
+                    notice = Some(format!("Evaluation: {} for {}", score, board.turn()))
+                }
+                Ok((_, None)) => error = Some("the engine gave no evaluation".to_string()),
+                Err(e) => error = Some(e.to_string()),
+            },
+
             Some(turn) => {
-                error = match board.move_piece(turn) {
-                    Ok(_) => None,
-                    Err(e) => Some(e.to_string()),
+                let side = Side::to_move(&board);
+                let before = board.to_fen();
+
+                let played = parse_move(&board, turn)
+                    .and_then(|mv| ask_promotion(events, &board, mv))
+                    .and_then(|mv| {
+                        let mv = mv.to_string();
+                        board.move_piece(&mv).map_err(|e| e.to_string())?;
+                        Ok(mv)
+                    });
+
+                error = match played {
+                    Ok(mv) => {
+                        history.push(before, &mv, &board);
+
+                        if let Some(clock) = &mut clock {
+                            clock.press();
+                        }
+
+                        record.push(&mv, clock.as_ref().map(|clock| clock.remaining(side)));
+                        None
+                    }
+                    Err(e) => Some(e),
                 }
             }
             _ => {}
         }
+    };
+
+    if let Some(outcome) = &outcome {
+        banner(outcome);
+    }
+
+    // a game that never started is not worth keeping
+    record.result = outcome.clone();
+    if !record.is_empty() {
+        archive(&record);
+    }
+
+    Ok((record, outcome))
+}
+
+/// Runs the clock of the side to move on `board`, after moves were taken
+/// back or played again.
+fn restart(clock: &mut Option<Clock>, board: &Board) {
+    if let Some(clock) = clock {
+        clock.start(Side::to_move(board));
+    }
+}
+
+/// Announces how a game ended, so it cannot be missed.
+fn banner(outcome: &Outcome) {
+    let text = format!("  Game over: {}  ", outcome);
+    let line = "═".repeat(text.chars().count());
+
+    println!("\n╔{}╗\n║{}║\n╚{}╝", line, text.bold(), line);
+}
+
+/// What to do once a game is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AfterGame {
+    Rematch,
+    Quit,
+}
+
+/// Offers a rematch once a game is over, or to save it. Answers are read
+/// with `read`, which gives `None` once there is no more input.
+fn post_game(record: &Record, mut read: impl FnMut() -> Option<String>) -> AfterGame {
+    loop {
+        println!("\n{}", "rematch, pgn [file], quit".dimmed());
+        print!("> ");
+
+        // flush stdout
+        std::io::stdout().flush().unwrap();
+
+        let Some(input) = read() else {
+            return AfterGame::Quit;
+        };
+
+        let mut cmd = input.split_whitespace();
+
+        match cmd.next() {
+            None => {}
+            Some("rematch") => return AfterGame::Rematch,
+            Some("q" | "quit" | "exit") => return AfterGame::Quit,
+
+            Some("pgn") => {
+                let filename = cmd.next().unwrap_or("game.pgn");
+
+                match record.save(filename) {
+                    Ok(_) => println!("Saved the game to {}", filename),
+                    Err(e) => println!("{}", format!("Could not save {}: {}", filename, e).red()),
+                }
+            }
+
+            Some(other) => println!("{}", format!("unknown command {:?}", other).red()),
+        }
+    }
+}
+
+/// The move `input` stands for on `board`, either in the coordinate
+/// notation `move_piece` takes or in SAN like `Nf3`. It may still need the
+/// piece a pawn promotes to.
+fn parse_move(board: &Board, input: &str) -> Result<Move, String> {
+    match input.parse::<Move>() {
+        Ok(mv) => Ok(mv),
+        Err(_) => Position::of(board).parse_san(input),
+    }
+}
+
+const PROMOTION: &str = "Promote to (q)ueen, (r)ook, (b)ishop or k(n)ight?";
+
+/// Asks which piece the pawn of `mv` promotes to, unless it says already.
+fn ask_promotion(events: &Events, board: &Board, mv: Move) -> Result<Move, String> {
+    if !Position::of(board).needs_promotion(mv) {
+        return Ok(mv);
+    }
+
+    print!("{} ", PROMOTION.bold());
+    std::io::stdout().flush().unwrap();
+
+    let input = events
+        .line(None)
+        .ok_or_else(|| "standard input is closed".to_string())?;
+
+    Ok(Move {
+        promotion: Some(promotion_piece(&input)?),
+        ..mv
+    })
+}
+
+/// The piece a pawn promotes to, by its letter or name.
+fn promotion_piece(input: &str) -> Result<Kind, String> {
+    match input.trim().to_lowercase().as_str() {
+        "q" | "queen" => Ok(Kind::Queen),
+        "r" | "rook" => Ok(Kind::Rook),
+        "b" | "bishop" => Ok(Kind::Bishop),
+        "n" | "knight" => Ok(Kind::Knight),
+        other => Err(format!(
+            "a pawn cannot promote to {:?}, pick q, r, b or n",
+            other
+        )),
+    }
+}
+
+/// Refuses a move of a peer that does not say what its pawn promotes to,
+/// which both boards could fill in differently.
+fn check_promotion(board: &Board, mv: &str) -> Result<(), String> {
+    match mv.parse::<Move>() {
+        Ok(parsed) if Position::of(board).needs_promotion(parsed) => Err(format!(
+            "{} does not say which piece the pawn promotes to",
+            mv
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Appends a finished game to the PGN archive, and tells where it went.
+fn archive(record: &Record) {
+    match record.archive() {
+        Ok(_) => println!("Saved the game to {}", pgn::ARCHIVE),
+        Err(e) => println!("{}", format!("Could not save the game: {}", e).red()),
     }
-    Ok(())
 }
 
-fn draw_for_white(board: &Board) {
-    println!("  ａｂｃｄｅｆｇｈ");
+/// Who plays the moves of the computer and answers `hint` and `eval`.
+enum Computer {
+    /// The built-in engine, playing at a level.
+    BuiltIn(u8),
+    External(uci::External),
+}
+
+impl Computer {
+    fn name(&self) -> String {
+        match self {
+            Computer::BuiltIn(level) => format!("chess-cli level {}", level),
+            Computer::External(engine) => engine.name().to_string(),
+        }
+    }
+
+    /// Searches the position on `board` within `limits`, for the best move
+    /// and its score.
+    fn analyse(
+        &mut self,
+        board: &Board,
+        limits: Limits,
+    ) -> Result<(Option<Move>, Option<Score>), Error> {
+        match self {
+            Computer::BuiltIn(_) => {
+                let mut score = None;
+                let best = engine::search(
+                    &Position::of(board),
+                    limits,
+                    &AtomicBool::new(false),
+                    |info| score = Some(Score::new(info.score)),
+                );
+
+                Ok((best, score))
+            }
+            Computer::External(engine) => Ok(engine.analyse(&board.to_fen(), limits)?),
+        }
+    }
+
+    /// The move to play on `board`, which must have a legal move.
+    fn best_move(&mut self, board: &Board, limits: Limits) -> Result<Move, Error> {
+        match self.analyse(board, limits)?.0 {
+            Some(mv) => Ok(mv),
+            None => {
+                let error = "the engine did not come up with a move";
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData, error).into())
+            }
+        }
+    }
+
+    /// How to search for a move to play, the built-in engine is weakened to
+    /// its level.
+    fn limits(&self) -> Limits {
+        match self {
+            Computer::BuiltIn(level) => Limits::level(*level),
+            Computer::External(_) => Limits::level(*LEVELS.end()),
+        }
+    }
+}
+
+/// How to search for a hint or an eval, at full strength.
+fn analysis() -> Limits {
+    Limits {
+        time: Some(Duration::from_secs(1)),
+        ..Limits::level(*LEVELS.end())
+    }
+}
+
+/// Lets the computer play a move for the side to move on `board`. Returns
+/// the outcome instead when there is no move left, or its flag fell.
+fn computer_move(
+    board: &mut Board,
+    history: &mut History,
+    clock: Option<&mut Clock>,
+    computer: &mut Computer,
+    record: &mut Record,
+) -> Result<Option<Outcome>, Error> {
+    let position = Position::of(board);
+    let side = position.turn();
+
+    if let Some(outcome) = Outcome::of(&position) {
+        return Ok(Some(outcome));
+    }
+
+    let mut limits = computer.limits();
+
+    // leave enough time for the rest of the game
+    if let Some(clock) = &clock {
+        let budget = clock.remaining(side) / 30;
+        limits.time = limits.time.map(|time| time.min(budget));
+    }
+
+    let mv = computer.best_move(board, limits)?.to_string();
+    let before = board.to_fen();
+    board.move_piece(&mv)?;
+    history.push(before, &mv, board);
+
+    if let Some(clock) = clock {
+        clock.press();
+        record.push(&mv, Some(clock.remaining(side)));
+
+        if clock.flagged() == Some(side) {
+            return Ok(Some(Outcome::out_of_time(side)));
+        }
+    } else {
+        record.push(&mv, None);
+    }
+
+    Ok(None)
+}
+
+/// Squares the renderers pick out from the rest of the board.
+#[derive(Debug, Clone, Default)]
+pub struct Highlights {
+    /// The last move, whose squares are tinted.
+    pub last_move: Option<Move>,
+    /// The square of the king in check.
+    pub check: Option<Square>,
+    /// Where the piece picked with `moves` can go.
+    pub destinations: Vec<Square>,
+}
+
+impl Highlights {
+    /// The highlights of the position on `board`, reached with `last_move`.
+    pub fn new(board: &Board, last_move: Option<Move>) -> Highlights {
+        let position = Position::of(board);
+
+        Highlights {
+            last_move,
+            check: position
+                .king(position.turn())
+                .filter(|_| position.in_check()),
+            destinations: Vec::new(),
+        }
+    }
+
+    /// The background of `square`, which is `color` unless highlighted.
+    fn tint(&self, square: Square, color: Color) -> Color {
+        let light = color == Color::White;
+
+        if self.check == Some(square) {
+            Color::Red
+        } else if self.destinations.contains(&square) {
+            if light {
+                Color::BrightGreen
+            } else {
+                Color::Green
+            }
+        } else if self
+            .last_move
+            .is_some_and(|mv| mv.from == square || mv.to == square)
+        {
+            if light {
+                Color::BrightYellow
+            } else {
+                Color::Yellow
+            }
+        } else {
+            color
+        }
+    }
+}
+
+/// The squares the piece on the square named `name` can move to, for the
+/// `moves` command.
+fn destinations(board: &Board, name: Option<&str>) -> Result<Vec<Square>, String> {
+    let name = name.ok_or("expected a square after moves")?;
+    let square =
+        position::parse_square(name).ok_or_else(|| format!("{:?} is not a square", name))?;
+
+    let position = Position::of(board);
+
+    let Some(piece) = position.piece_at(square) else {
+        return Err(format!("there is no piece on {}", name));
+    };
+
+    if piece.side != position.turn() {
+        return Err(format!(
+            "the {} on {} is {}'s, it is {}'s turn",
+            piece.kind.name(),
+            name,
+            piece.side,
+            position.turn()
+        ));
+    }
+
+    let destinations: Vec<Square> = position
+        .legal_moves()
+        .into_iter()
+        .filter(|mv| mv.from == square)
+        .map(|mv| mv.to)
+        .collect();
+
+    if destinations.is_empty() {
+        return Err(format!("the {} on {} cannot move", piece.kind.name(), name));
+    }
+
+    Ok(destinations)
+}
+
+fn draw(board: &Board, side: Side, panel: &[String], highlights: &Highlights) {
+    match side {
+        Side::White => draw_for_white(board, panel, highlights),
+        Side::Black => draw_for_black(board, panel, highlights),
+    }
+}
+
+/// The next line of a panel drawn beside the board, if any.
+fn beside(panel: &mut std::slice::Iter<String>) -> String {
+    panel
+        .next()
+        .map(|line| format!("    {}", line))
+        .unwrap_or_default()
+}
+
+fn draw_for_white(board: &Board, panel: &[String], highlights: &Highlights) {
+    let mut panel = panel.iter();
+
+    println!("  ａｂｃｄｅｆｇｈ  {}", beside(&mut panel));
     for rank in 0..8 {
         let rank = 8 - rank;
         print!("{} ", rank);
@@ -232,6 +1092,7 @@ fn draw_for_white(board: &Board) {
             } else {
                 Color::BrightBlue
             };
+            let square_color = highlights.tint((rank - 1) * 8 + file, square_color);
             let piece = board.get_piece(file, rank - 1);
 
             if piece.is_some() {
@@ -246,13 +1107,20 @@ fn draw_for_white(board: &Board) {
 
             print!("{}", " ".on_color(square_color));
         }
-        println!(" {}", rank);
+        println!(" {}{}", rank, beside(&mut panel));
+    }
+    println!("  ａｂｃｄｅｆｇｈ  {}", beside(&mut panel));
+
+    // a panel longer than the board goes on below it
+    for line in panel {
+        println!("{:20}    {}", "", line);
     }
-    println!("  ａｂｃｄｅｆｇｈ");
 }
 
-fn draw_for_black(board: &Board) {
-    println!("  ｈｇｆｅｄｃｂａ");
+fn draw_for_black(board: &Board, panel: &[String], highlights: &Highlights) {
+    let mut panel = panel.iter();
+
+    println!("  ｈｇｆｅｄｃｂａ  {}", beside(&mut panel));
     for rank in 0..8 {
         let rank = 1 + rank;
         print!("{} ", rank);
@@ -262,6 +1130,7 @@ fn draw_for_black(board: &Board) {
             } else {
                 Color::BrightBlue
             };
+            let square_color = highlights.tint((rank - 1) * 8 + file, square_color);
             let piece = board.get_piece(file, rank - 1);
 
             if piece.is_some() {
@@ -276,7 +1145,12 @@ fn draw_for_black(board: &Board) {
 
             print!("{}", " ".on_color(square_color));
         }
-        println!(" {}", rank);
+        println!(" {}{}", rank, beside(&mut panel));
+    }
+    println!("  ｈｇｆｅｄｃｂａ  {}", beside(&mut panel));
+
+    // a panel longer than the board goes on below it
+    for line in panel {
+        println!("{:20}    {}", "", line);
     }
-    println!("  ｈｇｆｅｄｃｂａ");
 }
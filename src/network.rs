@@ -0,0 +1,788 @@
+//! Games against a player on the other end of a TCP connection, either
+//! hosted by this process or joined on a server.
+
+use std::{
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use chess_lib::chess::{Board, Error};
+use colored::*;
+
+use crate::{
+    banner, chat_panel, check_promotion,
+    clock::{self, Clock},
+    destinations, draw,
+    events::{Event, Events},
+    history::History,
+    move_panel, parse_move,
+    pgn::{self, Record},
+    position::{Move, Position, Square},
+    post_game, promotion_piece,
+    protocol::{self, ColorPreference, Connection, Message, Offer, Terms, Writer},
+    restart, with_moves, AfterGame, Args, Highlights, Outcome, Side, PROMOTION,
+};
+
+pub fn client(host: &str, port: u16, args: &Args) -> Result<(), Error> {
+    // only the first game can be resumed, a rematch is a new game
+    let mut resume = args.resume.zip(args.token);
+
+    loop {
+        let mut connection = Connection::new(TcpStream::connect(format!("{}:{}", host, port))?)?;
+
+        let terms = match resume.take() {
+            Some((game, token)) => protocol::resume(&mut connection, &args.name, game, token),
+            None => {
+                println!("Waiting for an opponent...");
+
+                let code = args.join.as_deref();
+                protocol::join(
+                    &mut connection,
+                    &args.name,
+                    args.color,
+                    code,
+                    args.time,
+                    args.start(),
+                )
+            }
+        };
+
+        let terms = match terms {
+            Ok(terms) => terms,
+            Err(e) => {
+                println!("{}", format!("Could not join game: {}", e).red());
+                return Ok(());
+            }
+        };
+
+        let (game, token) = (terms.game, terms.token);
+
+        match play(connection, terms, &args.name) {
+            Ok(AfterGame::Rematch) => {}
+            Ok(AfterGame::Quit) => return Ok(()),
+            Err(e) => {
+                // only a lobby holds on to the game for the player to return
+                let message = match token {
+                    Some(token) => format!(
+                        "Lost the game ({}), rejoin with {}",
+                        e,
+                        resume_arguments(game, token)
+                    ),
+                    None => format!("Lost the game ({})", e),
+                };
+                println!("\n{}", message.red());
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The arguments to take a seat in a lobby game again.
+fn resume_arguments(game: u64, token: u64) -> String {
+    format!("--resume {} --token {}", game, token)
+}
+
+pub fn spectator(host: &str, port: u16, game: u64, color: ColorPreference) -> Result<(), Error> {
+    let mut connection = Connection::new(TcpStream::connect(format!("{}:{}", host, port))?)?;
+
+    let (white, black, fen) = match protocol::spectate(&mut connection, game) {
+        Ok(game) => game,
+        Err(e) => {
+            println!("{}", format!("Could not watch game: {}", e).red());
+            return Ok(());
+        }
+    };
+
+    let mut board = Board::from_fen(&fen)?;
+    let mut clock: Option<(Duration, Duration)> = None;
+    let mut chat: Vec<String> = Vec::new();
+    let mut last_move: Option<Move> = None;
+
+    // watch from white's perspective, unless asked otherwise
+    let side = match color {
+        ColorPreference::Black => Side::Black,
+        _ => Side::White,
+    };
+
+    loop {
+        // clear screen
+        print!("{}[2J", 27 as char);
+
+        println!(
+            "Watching game {}: {} vs {}",
+            game,
+            white.bold(),
+            black.bold()
+        );
+
+        draw(
+            &board,
+            side,
+            &chat_panel(&chat),
+            &Highlights::new(&board, last_move),
+        );
+
+        if let Some((white, black)) = clock {
+            let (white, black) = (clock::format_time(white), clock::format_time(black));
+            println!("\nWhite {}  Black {}", white, black);
+        }
+
+        println!("\n{} to move", board.turn().to_string().bold());
+
+        match connection.receive() {
+            Ok(Message::Move(mv)) => {
+                board.move_piece(&mv)?;
+                last_move = mv.parse().ok();
+            }
+            // a takeback, which no longer tells the last move
+            Ok(Message::Position(fen)) => {
+                board = Board::from_fen(&fen)?;
+                last_move = None;
+            }
+            Ok(Message::Clock { white, black }) => clock = Some((white, black)),
+            Ok(Message::Chat { side, text }) => {
+                let name = match side {
+                    Side::White => &white,
+                    Side::Black => &black,
+                };
+                chat.push(format!("{}: {}", name, text));
+            }
+            Ok(Message::GameOver(outcome)) => {
+                banner(&outcome);
+                return Ok(());
+            }
+            Ok(other) => return Err(protocol::unexpected(&other).into()),
+            Err(_) => {
+                println!("\nThe game has ended");
+                return Ok(());
+            }
+        };
+    }
+}
+
+pub fn server(port: u16, args: &Args) -> Result<(), Error> {
+    let server = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    println!("Server started on port {}", port);
+
+    for (game, stream) in (1..).zip(server.incoming()) {
+        let mut connection = Connection::new(stream?)?;
+
+        let terms = match protocol::accept(
+            &mut connection,
+            &args.name,
+            args.color,
+            game,
+            args.time,
+            args.start(),
+        ) {
+            Ok(terms) => terms,
+            Err(e) => {
+                println!("{}", format!("Rejected connection: {}", e).red());
+                continue;
+            }
+        };
+
+        // a lost connection ends the game, not the host
+        match play(connection, terms, &args.name) {
+            Ok(AfterGame::Rematch) => {}
+            Ok(AfterGame::Quit) => break,
+            Err(e) => println!("\n{}", format!("Lost the game ({})", e).red()),
+        }
+
+        println!("Waiting for an opponent...");
+    }
+
+    Ok(())
+}
+
+/// Plays a game as `name` against the player on the other end of
+/// `connection`, under the negotiated `terms`. Once it is over, asks what
+/// to do next.
+fn play(connection: Connection, terms: Terms, name: &str) -> Result<AfterGame, Error> {
+    let (reader, writer) = connection.split();
+
+    let mut game = Game::new(terms, writer, name)?;
+    let events = Events::new(Some(reader));
+    let result = game.run(&events);
+
+    if let Some(clock) = &mut game.clock {
+        clock.stop();
+    }
+
+    // also ends the thread reading from the connection
+    game.writer.close();
+
+    let outcome = result?;
+    banner(&outcome);
+
+    game.record.result = Some(outcome);
+    match game.record.archive() {
+        Ok(_) => println!("Saved the game to {}", pgn::ARCHIVE),
+        Err(e) => println!("{}", format!("Could not save the game: {}", e).red()),
+    }
+
+    // the connection is closed, whatever the opponent still sends is dropped
+    Ok(post_game(&game.record, || events.line(None)))
+}
+
+/// A request of ours the opponent has not answered yet.
+enum Pending {
+    /// A move we sent, along with the position before it.
+    Move {
+        mv: String,
+        before: String,
+    },
+    Offer(Offer),
+}
+
+/// A game in progress against a remote player.
+struct Game {
+    side: Side,
+    id: u64,
+    opponent: String,
+    writer: Writer,
+    board: Board,
+    /// The positions played through, to take moves back and tell draws.
+    history: History,
+    record: Record,
+    clock: Option<Clock>,
+    chat: Vec<String>,
+    error: Option<String>,
+    /// The answer to a command, shown once.
+    notice: Option<String>,
+    /// Draw the board from the opponent's perspective.
+    flipped: bool,
+    pending: Option<Pending>,
+    /// An offer of the opponent we have not answered yet.
+    offered: Option<Offer>,
+    /// The token to resume the game with. Only a lobby server hands one
+    /// out, and it referees the game, so its results are final.
+    token: Option<u64>,
+    /// A move of ours waiting for the piece its pawn promotes to.
+    promoting: Option<Move>,
+    /// Where the piece picked with `moves` can go, shown once.
+    selected: Vec<Square>,
+}
+
+impl Game {
+    fn new(terms: Terms, writer: Writer, name: &str) -> Result<Game, Error> {
+        let Terms {
+            side,
+            game,
+            time,
+            opponent,
+            start,
+            moves,
+            clock: remaining,
+            token,
+        } = terms;
+
+        let mut board = Board::from_fen(&start)?;
+        let mut history = History::new(&board);
+
+        let event = format!("Game {}", game);
+        let mut record = match side {
+            Side::White => Record::new(&event, name, &opponent, &board),
+            Side::Black => Record::new(&event, &opponent, name, &board),
+        };
+
+        // replay the moves of a resumed game, whose clock times are lost
+        for mv in &moves {
+            let before = board.to_fen();
+            board.move_piece(mv)?;
+            history.push(before, mv, &board);
+            record.push(mv, None);
+        }
+
+        let mut clock = time.map(Clock::new);
+        if let Some(clock) = &mut clock {
+            if let Some((white, black)) = remaining {
+                clock.set(white, black);
+            }
+
+            clock.start(Side::to_move(&board));
+        }
+
+        Ok(Game {
+            side,
+            id: game,
+            opponent,
+            writer,
+            board,
+            history,
+            record,
+            clock,
+            chat: Vec::new(),
+            error: None,
+            notice: None,
+            flipped: false,
+            pending: None,
+            offered: None,
+            token,
+            promoting: None,
+            selected: Vec::new(),
+        })
+    }
+
+    /// Reacts to whatever happens first, the local player typing a command or
+    /// the opponent sending a message, until the game ends.
+    fn run(&mut self, events: &Events) -> Result<Outcome, Error> {
+        loop {
+            self.draw();
+
+            // only our own flag is watched, the opponent reports theirs
+            let timeout = self
+                .clock
+                .as_ref()
+                .filter(|_| self.our_turn())
+                .map(|clock| clock.remaining(self.side));
+
+            let outcome = match events.next(timeout) {
+                None => self.flag_fell()?,
+                Some(Event::Input(input)) => self.command(input.trim())?,
+                Some(Event::Message(message)) => self.message(message?)?,
+            };
+
+            if let Some(outcome) = outcome {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        // clear screen
+        print!("{}[2J", 27 as char);
+
+        println!(
+            "Game {}: playing {} against {}",
+            self.id,
+            self.side,
+            self.opponent.bold()
+        );
+
+        if let Some(token) = self.token {
+            let hint = format!("to rejoin: {}", resume_arguments(self.id, token));
+            println!("{}", hint.dimmed());
+        }
+
+        if let Some(error) = &self.error {
+            println!("\n{}\n", error.red());
+        }
+
+        let perspective = if self.flipped {
+            self.side.opponent()
+        } else {
+            self.side
+        };
+        let panel = with_moves(move_panel(&self.history), chat_panel(&self.chat));
+        let highlights = Highlights {
+            destinations: std::mem::take(&mut self.selected),
+            ..Highlights::new(&self.board, self.history.last_move())
+        };
+        draw(&self.board, perspective, &panel, &highlights);
+
+        if let Some(clock) = &self.clock {
+            println!("\n{}", clock);
+        }
+
+        println!("\n{} to move:", self.board.turn().to_string().bold());
+
+        if let Some(notice) = self.notice.take() {
+            println!("{}", notice);
+        }
+
+        match (&self.pending, self.offered) {
+            (_, Some(offer)) => {
+                let question = match offer {
+                    Offer::Draw => format!("{} offers a draw", self.opponent),
+                    Offer::Takeback(_) => format!("{} asks to take back a move", self.opponent),
+                };
+                println!("{}, accept or decline?", question.bold());
+            }
+            (Some(Pending::Offer(_)), None) => {
+                println!("Waiting for {} to answer your offer", self.opponent)
+            }
+            _ => {}
+        }
+
+        if let Some(reason) = self.history.claimable_draw().filter(|_| self.our_turn()) {
+            println!("You may claim a draw by {}, type claim", reason);
+        }
+
+        if self.promoting.is_some() {
+            println!("{}", PROMOTION.bold());
+        }
+
+        println!(
+            "{}",
+            "say <text>, flip, fen, moves <square>, save [file], pgn [file], draw, claim, takeback, resign, quit"
+                .dimmed()
+        );
+        print!("> ");
+
+        // flush stdout
+        io::stdout().flush().unwrap();
+    }
+
+    fn our_turn(&self) -> bool {
+        Side::to_move(&self.board) == self.side
+    }
+
+    /// Handles a command of the local player, which may be typed at any time.
+    fn command(&mut self, input: &str) -> Result<Option<Outcome>, Error> {
+        self.error = None;
+
+        if let Some(mv) = self.promoting.take() {
+            match promotion_piece(input) {
+                Ok(kind) => self.submit(Move {
+                    promotion: Some(kind),
+                    ..mv
+                })?,
+                Err(e) => self.error = Some(format!("{}, {} was not played", e, mv)),
+            }
+
+            return Ok(None);
+        }
+
+        let mut cmd = input.split_whitespace();
+
+        match cmd.next() {
+            None => {}
+
+            Some("say") => {
+                let text = input["say".len()..].trim().to_string();
+                self.chat.push(format!("You: {}", text));
+                self.send(&Message::Chat {
+                    side: self.side,
+                    text,
+                })?;
+            }
+
+            Some("flip") => self.flipped = !self.flipped,
+
+            Some("save") => {
+                let filename = cmd.next().unwrap_or("game.txt");
+                self.board.save(filename)?;
+            }
+
+            Some("fen") => self.notice = Some(self.board.to_fen()),
+
+            Some("moves") => match destinations(&self.board, cmd.next()) {
+                Ok(squares) => self.selected = squares,
+                Err(e) => self.error = Some(e),
+            },
+
+            // both boards have to stay the same
+            Some("setfen") => {
+                self.error =
+                    Some("the position of a network game can only be set up with --fen".to_string())
+            }
+
+            Some("pgn") => {
+                let filename = cmd.next().unwrap_or("game.pgn");
+
+                if let Err(e) = self.record.save(filename) {
+                    self.error = Some(format!("Could not save {}: {}", filename, e));
+                }
+            }
+
+            // leaving a game in progress gives it up
+            Some("resign" | "q" | "quit" | "exit") => {
+                self.send(&Message::Resign(self.side))?;
+                return Ok(Some(Outcome::resignation(self.side)));
+            }
+
+            Some(answer @ ("accept" | "decline")) => match self.offered.take() {
+                Some(offer) if answer == "accept" => {
+                    self.send(&Message::Accept)?;
+
+                    match offer {
+                        Offer::Draw => return Ok(Some(Outcome::Draw("draw by agreement".into()))),
+                        Offer::Takeback(plies) => self.take_back(plies)?,
+                    }
+                }
+                Some(_) => self.send(&Message::Decline)?,
+                None => self.error = Some(format!("{} has not offered anything", self.opponent)),
+            },
+
+            Some("takeback") if self.pending.is_none() => {
+                // on our turn that is the reply of the opponent and our own
+                // move, on theirs only our last move
+                let plies = if self.our_turn() { 2 } else { 1 };
+
+                if self.history.len() < plies {
+                    self.error = Some("there is no move of yours to take back".to_string());
+                } else {
+                    self.offer(Offer::Takeback(plies))?;
+                }
+            }
+
+            Some(_) if !self.our_turn() => {
+                self.error = Some(format!("wait for {} to move", self.opponent))
+            }
+
+            Some(_) if self.pending.is_some() => {
+                self.error = Some(format!("wait for {} to answer", self.opponent))
+            }
+
+            Some("draw") => self.offer(Offer::Draw)?,
+
+            Some("claim") => match self.history.claimable_draw() {
+                Some(reason) => {
+                    let outcome = Outcome::Draw(reason.to_string());
+                    self.send(&Message::GameOver(outcome.clone()))?;
+                    return Ok(Some(outcome));
+                }
+                None => self.error = Some("there is no draw to claim".to_string()),
+            },
+
+            Some(_) => self.play_move(input)?,
+        }
+
+        Ok(None)
+    }
+
+    fn offer(&mut self, offer: Offer) -> Result<(), Error> {
+        self.send(&Message::Offer(offer))?;
+        self.pending = Some(Pending::Offer(offer));
+
+        Ok(())
+    }
+
+    fn play_move(&mut self, input: &str) -> Result<(), Error> {
+        match parse_move(&self.board, input) {
+            Ok(mv) if Position::of(&self.board).needs_promotion(mv) => self.promoting = Some(mv),
+            Ok(mv) => self.submit(mv)?,
+            Err(e) => self.error = Some(e),
+        }
+
+        Ok(())
+    }
+
+    /// Plays our move `mv` and sends it to the opponent.
+    fn submit(&mut self, mv: Move) -> Result<(), Error> {
+        // the peer only understands coordinate notation
+        let mv = mv.to_string();
+
+        // validate locally first, so an illegal move never reaches the peer
+        let before = self.board.to_fen();
+        if let Err(e) = self.board.move_piece(&mv) {
+            self.error = Some(e.to_string());
+            return Ok(());
+        }
+
+        // moving on declines the takeback the opponent asked for
+        if self.offered.take().is_some() {
+            self.send(&Message::Decline)?;
+        }
+
+        if let Some(clock) = &mut self.clock {
+            clock.press();
+            let (white, black) = (clock.remaining(Side::White), clock.remaining(Side::Black));
+            self.send(&Message::Clock { white, black })?;
+        }
+
+        self.send(&Message::Move(mv.clone()))?;
+        self.pending = Some(Pending::Move { mv, before });
+
+        Ok(())
+    }
+
+    /// Handles a message of the opponent.
+    fn message(&mut self, message: Message) -> Result<Option<Outcome>, Error> {
+        match (message, self.pending.take()) {
+            (Message::Chat { text, .. }, pending) => {
+                self.pending = pending;
+                self.chat.push(format!("{}: {}", self.opponent, text));
+            }
+
+            // only the opponent can resign for themselves
+            (Message::Resign(resigned), _) if resigned == self.side.opponent() => {
+                return Ok(Some(Outcome::resignation(resigned)))
+            }
+
+            // our takeback request may cross the move of the opponent
+            (Message::Clock { white, black }, pending @ (None | Some(Pending::Offer(_))))
+                if !self.our_turn() =>
+            {
+                self.pending = pending;
+
+                if let Some(clock) = &mut self.clock {
+                    clock.set(white, black);
+
+                    if clock.remaining(self.side.opponent()).is_zero() {
+                        return Ok(Some(Outcome::out_of_time(self.side.opponent())));
+                    }
+                }
+            }
+
+            (Message::Move(mv), pending @ (None | Some(Pending::Offer(_)))) if !self.our_turn() => {
+                self.pending = pending;
+
+                let before = self.board.to_fen();
+
+                let played = check_promotion(&self.board, &mv)
+                    .and_then(|_| self.board.move_piece(&mv).map_err(|e| e.to_string()));
+
+                let reply = match played {
+                    Ok(_) => {
+                        self.history.push(before, &mv, &self.board);
+
+                        // the opponent reported their clock right before the move
+                        let left = self
+                            .clock
+                            .as_ref()
+                            .map(|clock| clock.remaining(self.side.opponent()));
+                        self.record.push(&mv, left);
+
+                        if let Some(clock) = &mut self.clock {
+                            clock.start(self.side);
+                        }
+
+                        Message::MoveAccepted(protocol::checksum(&self.board))
+                    }
+                    Err(e) => Message::MoveRejected(e),
+                };
+
+                self.send(&reply)?;
+
+                if let Some(outcome) = self.game_over() {
+                    // the opponent may be gone already, they know anyway
+                    let _ = self.writer.send(&Message::GameOver(outcome.clone()));
+                    return Ok(Some(outcome));
+                }
+            }
+
+            (Message::Offer(offer), None) if self.offered.is_none() && self.offerable(offer) => {
+                self.offered = Some(offer);
+            }
+
+            // an offer that crossed our move or our own offer no longer fits
+            (Message::Offer(_), pending) if self.offered.is_none() => {
+                self.pending = pending;
+                self.send(&Message::Decline)?;
+            }
+
+            (Message::MoveAccepted(checksum), Some(Pending::Move { mv, before })) => {
+                if checksum != protocol::checksum(&self.board) {
+                    return Err(diverged().into());
+                }
+
+                self.history.push(before, &mv, &self.board);
+
+                let left = self.clock.as_ref().map(|clock| clock.remaining(self.side));
+                self.record.push(&mv, left);
+
+                if let Some(outcome) = self.game_over() {
+                    return Ok(Some(outcome));
+                }
+            }
+
+            (Message::GameOver(outcome), _) if self.token.is_some() || self.agrees(&outcome) => {
+                return Ok(Some(outcome))
+            }
+
+            (Message::MoveRejected(reason), Some(Pending::Move { mv, before })) => {
+                self.board = Board::from_fen(&before)?;
+
+                // still our turn, so our clock keeps running
+                if let Some(clock) = &mut self.clock {
+                    clock.start(self.side);
+                }
+
+                self.error = Some(format!("{} rejected {}: {}", self.opponent, mv, reason));
+            }
+
+            (Message::Accept, Some(Pending::Offer(offer))) => match offer {
+                Offer::Draw => return Ok(Some(Outcome::Draw("draw by agreement".to_string()))),
+                Offer::Takeback(plies) => self.take_back(plies)?,
+            },
+
+            (Message::Withdraw, pending) if self.offered.is_some() => {
+                self.pending = pending;
+                self.offered = None;
+                self.error = Some(format!("{} left, their offer was withdrawn", self.opponent));
+            }
+
+            (Message::Decline, Some(Pending::Offer(offer))) => {
+                self.error = Some(match offer {
+                    Offer::Draw => format!("{} declined your draw offer", self.opponent),
+                    Offer::Takeback(_) => {
+                        format!("{} declined your takeback request", self.opponent)
+                    }
+                });
+            }
+
+            (other, _) => return Err(protocol::unexpected(&other).into()),
+        }
+
+        Ok(None)
+    }
+
+    /// Whether the opponent may make `offer` now: a draw on their turn, or
+    /// taking back moves the last of which is theirs.
+    fn offerable(&self, offer: Offer) -> bool {
+        match offer {
+            Offer::Draw => !self.our_turn(),
+            // taking back an odd number of plies ends on our turn
+            Offer::Takeback(plies) => {
+                plies > 0 && self.history.len() >= plies && self.our_turn() == (plies % 2 == 1)
+            }
+        }
+    }
+
+    /// Whether `outcome`, announced by the opponent, is how the game ended:
+    /// a draw they may claim on their turn, or what our own board says.
+    fn agrees(&self, outcome: &Outcome) -> bool {
+        let claimed = match outcome {
+            Outcome::Draw(reason) => {
+                !self.our_turn() && self.history.claimable_draw() == Some(reason.as_str())
+            }
+            Outcome::Win(..) => false,
+        };
+
+        claimed || self.game_over().as_ref() == Some(outcome)
+    }
+
+    /// How the game ended with the last move, if it did.
+    fn game_over(&self) -> Option<Outcome> {
+        Outcome::after(&Position::of(&self.board), &self.history)
+    }
+
+    fn take_back(&mut self, plies: usize) -> Result<(), Error> {
+        self.history.take_back(&mut self.board, plies)?;
+        self.record.truncate(self.history.len());
+        restart(&mut self.clock, &self.board);
+
+        Ok(())
+    }
+
+    /// Reports that our own flag fell, which ends the game.
+    fn flag_fell(&mut self) -> Result<Option<Outcome>, Error> {
+        let Some(clock) = &mut self.clock else {
+            return Ok(None);
+        };
+
+        if clock.flagged() != Some(self.side) {
+            return Ok(None);
+        }
+
+        clock.stop();
+        let (white, black) = (clock.remaining(Side::White), clock.remaining(Side::Black));
+        self.send(&Message::Clock { white, black })?;
+
+        Ok(Some(Outcome::out_of_time(self.side)))
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), Error> {
+        Ok(self.writer.send(message)?)
+    }
+}
+
+fn diverged() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the boards of both players no longer match",
+    )
+}
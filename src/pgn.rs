@@ -0,0 +1,439 @@
+//! Portable Game Notation, the text format for chess games that any other
+//! chess tool can read.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    mem::take,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chess_lib::chess::Board;
+
+use crate::{
+    position::{Move, Position, START},
+    Outcome, Side,
+};
+
+/// Finished games are appended to this file.
+pub const ARCHIVE: &str = "games.pgn";
+
+/// A game being played, kept so it can be written as PGN.
+#[derive(Debug, Clone)]
+pub struct Record {
+    event: String,
+    white: String,
+    black: String,
+    date: String,
+    /// The FEN of the starting position.
+    start: String,
+    /// Every move played, in the notation `move_piece` takes, with the time
+    /// left on the clock of the player after it.
+    moves: Vec<(String, Option<Duration>)>,
+    pub result: Option<Outcome>,
+}
+
+impl Record {
+    pub fn new(event: &str, white: &str, black: &str, start: &Board) -> Record {
+        Record {
+            event: event.to_string(),
+            white: white.to_string(),
+            black: black.to_string(),
+            date: today(),
+            start: start.to_fen(),
+            moves: Vec::new(),
+            result: None,
+        }
+    }
+
+    pub fn push(&mut self, mv: &str, clock: Option<Duration>) {
+        self.moves.push((mv.to_string(), clock));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    /// Forgets all but the first `plies` moves, after taking moves back.
+    pub fn truncate(&mut self, plies: usize) {
+        self.moves.truncate(plies);
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        write!(File::create(path)?, "{}", self)
+    }
+
+    /// Appends the game to `ARCHIVE`.
+    pub fn archive(&self) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(ARCHIVE)?;
+
+        writeln!(file, "{}", self)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = self.result.as_ref().map_or("*", Outcome::score);
+
+        // the Seven Tag Roster, in its prescribed order
+        let tags = [
+            ("Event", self.event.as_str()),
+            ("Site", "?"),
+            ("Date", &self.date),
+            ("Round", "-"),
+            ("White", &self.white),
+            ("Black", &self.black),
+            ("Result", result),
+        ];
+
+        for (name, value) in tags {
+            writeln!(f, "[{} \"{}\"]", name, escape(value))?;
+        }
+
+        let start: Position = self.start.parse().expect("chess-lib writes valid FEN");
+
+        if !start.is_start() {
+            writeln!(f, "[SetUp \"1\"]")?;
+            writeln!(f, "[FEN \"{}\"]", self.start)?;
+        }
+
+        writeln!(f)?;
+
+        let mut position = start;
+        let mut tokens = Vec::new();
+
+        // black's moves are only numbered when something came in between
+        let mut numbered = false;
+
+        for (mv, clock) in &self.moves {
+            let Ok(mv) = mv.parse::<Move>() else {
+                break;
+            };
+
+            if position.turn() == Side::White {
+                tokens.push(format!("{}.", position.fullmove));
+            } else if !numbered {
+                tokens.push(format!("{}...", position.fullmove));
+            }
+
+            tokens.push(position.san(mv));
+            position.play(mv);
+            numbered = true;
+
+            if let Some(clock) = clock {
+                tokens.push(format!("{{[%clk {}]}}", format_clock(*clock)));
+                numbered = false;
+            }
+        }
+
+        if let Some(outcome) = &self.result {
+            tokens.push(format!("{{{}}}", outcome.reason()));
+        }
+
+        tokens.push(result.to_string());
+
+        // lines of movetext are kept below 80 characters
+        let mut line = String::new();
+
+        for token in tokens {
+            if !line.is_empty() && line.len() + 1 + token.len() > 79 {
+                writeln!(f, "{}", line)?;
+                line.clear();
+            }
+
+            if !line.is_empty() {
+                line.push(' ');
+            }
+
+            line.push_str(&token);
+        }
+
+        writeln!(f, "{}", line)
+    }
+}
+
+/// A game read from a PGN file, along its main line.
+#[derive(Debug, Clone)]
+pub struct Game {
+    pub tags: Vec<(String, String)>,
+    /// The FEN of the starting position.
+    pub start: String,
+    /// Every move of the main line, with its SAN.
+    pub moves: Vec<(Move, String)>,
+}
+
+impl Game {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Tag(String, String),
+    /// The start of a variation.
+    Open,
+    Close,
+    /// A move, a move number or a result.
+    Symbol(String),
+}
+
+/// Reads every game in `text`. Comments, NAGs and variations are skipped,
+/// only the main line of each game is kept.
+pub fn parse(text: &str) -> Result<Vec<Game>, String> {
+    let mut games = Vec::new();
+
+    let mut tags = Vec::new();
+    let mut moves = Vec::new();
+    let mut variations = 0;
+
+    for token in tokens(text)? {
+        match token {
+            Token::Tag(name, value) => {
+                // a game without a result ends where the next one begins
+                if !moves.is_empty() {
+                    games.push(game(games.len() + 1, take(&mut tags), take(&mut moves))?);
+                }
+
+                tags.push((name, value));
+            }
+
+            Token::Open => variations += 1,
+
+            Token::Close if variations == 0 => {
+                return Err(format!("game {}: unbalanced ')'", games.len() + 1))
+            }
+            Token::Close => variations -= 1,
+
+            Token::Symbol(_) if variations > 0 => {}
+
+            Token::Symbol(symbol) if matches!(symbol.as_str(), "1-0" | "0-1" | "1/2-1/2" | "*") => {
+                games.push(game(games.len() + 1, take(&mut tags), take(&mut moves))?);
+            }
+
+            Token::Symbol(symbol) => {
+                // move numbers like `12.` or `12...`, which may stick to the move
+                let san = match symbol.find(|c: char| !c.is_ascii_digit()) {
+                    None => "",
+                    Some(end) if end > 0 && symbol[end..].starts_with('.') => {
+                        symbol[end..].trim_start_matches('.')
+                    }
+                    Some(_) => &symbol,
+                };
+
+                if !san.is_empty() {
+                    moves.push(san.to_string());
+                }
+            }
+        }
+    }
+
+    if !tags.is_empty() || !moves.is_empty() {
+        games.push(game(games.len() + 1, tags, moves)?);
+    }
+
+    Ok(games)
+}
+
+/// Plays through the moves of the `number`th game of a file.
+fn game(number: usize, tags: Vec<(String, String)>, sans: Vec<String>) -> Result<Game, String> {
+    let start = tags
+        .iter()
+        .find(|(tag, _)| tag == "FEN")
+        .map_or(START, |(_, fen)| fen.as_str())
+        .to_string();
+
+    let mut position: Position = start
+        .parse()
+        .map_err(|e| format!("game {}: invalid FEN {:?}: {}", number, start, e))?;
+
+    let mut moves = Vec::new();
+
+    for san in sans {
+        let dots = if position.turn() == Side::White {
+            "."
+        } else {
+            "..."
+        };
+        let context = format!(
+            "game {}, move {}{} {}",
+            number, position.fullmove, dots, san
+        );
+
+        let mv = position
+            .parse_san(&san)
+            .map_err(|e| format!("{}: {}", context, e))?;
+
+        if position.needs_promotion(mv) {
+            return Err(format!("{}: the piece to promote to is missing", context));
+        }
+
+        moves.push((mv, position.san(mv)));
+        position.play(mv);
+    }
+
+    Ok(Game { tags, start, moves })
+}
+
+fn tokens(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        let at_line_start = line_start;
+        line_start = c == '\n';
+
+        match c {
+            _ if c.is_whitespace() => {}
+
+            // an escaped line, for the private use of other tools
+            '%' if at_line_start => {
+                chars.by_ref().find(|&c| c == '\n');
+                line_start = true;
+            }
+
+            ';' => {
+                chars.by_ref().find(|&c| c == '\n');
+                line_start = true;
+            }
+
+            '{' => {
+                if !chars.by_ref().any(|c| c == '}') {
+                    return Err("unterminated comment".to_string());
+                }
+            }
+
+            '[' => {
+                let mut name = String::new();
+                let mut value = String::new();
+                let mut quoted = false;
+
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated tag".to_string()),
+                        Some(']') if !quoted => break,
+                        Some('"') => quoted = !quoted,
+                        Some('\\') if quoted => value.extend(chars.next()),
+                        Some(c) if quoted => value.push(c),
+                        Some(c) => name.push(c),
+                    }
+                }
+
+                tokens.push(Token::Tag(name.trim().to_string(), value));
+            }
+
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+
+            // a numeric annotation glyph
+            '$' => while chars.next_if(char::is_ascii_digit).is_some() {},
+
+            _ => {
+                let mut symbol = c.to_string();
+
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !"[]{}();$".contains(*c))
+                {
+                    symbol.push(c);
+                }
+
+                tokens.push(Token::Symbol(symbol));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Formats the time left on a clock as `h:mm:ss`, as `%clk` wants it.
+fn format_clock(time: Duration) -> String {
+    let seconds = time.as_secs();
+
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The current date as `YYYY.MM.DD`, in UTC.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() / 86_400) as i64;
+
+    // days since 1970-01-01 to a civil date, after Howard Hinnant
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{}.{:02}.{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMES: &str = r#"[Event "Casual game"]
+[White "Anderssen"]
+[Black "Kieseritzky"]
+[Result "1-0"]
+
+1. e4 e5 2. f4 {the King's Gambit} exf4 $1 3. Bc4 (3. Nf3 g5 (3... d6) 4. h4)
+3... Qh4+ $2 ; a queen check
+4. Kf1 1-0
+
+[Event "Set up"]
+[SetUp "1"]
+[FEN "4k3/1P6/8/8/8/8/8/4K3 w - - 0 1"]
+%a line for other tools
+1. b8=Q+ Kd7 *
+"#;
+
+    #[test]
+    fn parses_several_games_along_the_main_line() {
+        let games = parse(GAMES).unwrap();
+        assert_eq!(games.len(), 2);
+
+        let sans: Vec<&str> = games[0].moves.iter().map(|(_, san)| san.as_str()).collect();
+        assert_eq!(sans, ["e4", "e5", "f4", "exf4", "Bc4", "Qh4+", "Kf1"]);
+        assert_eq!(games[0].tag("Black"), Some("Kieseritzky"));
+        assert_eq!(games[0].tag("Result"), Some("1-0"));
+        assert_eq!(games[0].start, START);
+
+        assert_eq!(games[1].start, "4k3/1P6/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(games[1].moves[0].0.to_string(), "b7b8q");
+        assert_eq!(games[1].moves.len(), 2);
+    }
+
+    #[test]
+    fn reports_broken_games() {
+        assert!(parse("1. e4 e5 2. Ke3")
+            .unwrap_err()
+            .contains("game 1, move 2. Ke3"));
+        assert!(parse("1. e4 ) e5").unwrap_err().contains("unbalanced"));
+        assert!(parse("1. e4 {open")
+            .unwrap_err()
+            .contains("unterminated comment"));
+        assert!(parse("[FEN \"4k3/1P6/8/8/8/8/8/4K3 w - - 0 1\"] 1. b8")
+            .unwrap_err()
+            .contains("promote"));
+    }
+}
@@ -0,0 +1,1128 @@
+//! Legal move generation on top of `Board`, which only checks the moves it
+//! is given. A `Position` is built from the FEN of a board and knows every
+//! move that can be played in it.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use chess_lib::chess::Board;
+
+use crate::Side;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+impl Kind {
+    /// The letter of the piece in FEN and SAN, in upper case.
+    pub fn letter(self) -> char {
+        match self {
+            Kind::Pawn => 'P',
+            Kind::Knight => 'N',
+            Kind::Bishop => 'B',
+            Kind::Rook => 'R',
+            Kind::Queen => 'Q',
+            Kind::King => 'K',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Pawn => "pawn",
+            Kind::Knight => "knight",
+            Kind::Bishop => "bishop",
+            Kind::Rook => "rook",
+            Kind::Queen => "queen",
+            Kind::King => "king",
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Kind> {
+        match letter.to_ascii_uppercase() {
+            'P' => Some(Kind::Pawn),
+            'N' => Some(Kind::Knight),
+            'B' => Some(Kind::Bishop),
+            'R' => Some(Kind::Rook),
+            'Q' => Some(Kind::Queen),
+            'K' => Some(Kind::King),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Piece {
+    pub side: Side,
+    pub kind: Kind,
+}
+
+/// A square of the board: 0 is a1, 7 is h1 and 63 is h8.
+pub type Square = usize;
+
+pub fn square_name(square: Square) -> String {
+    let file = (b'a' + (square % 8) as u8) as char;
+    let rank = (b'1' + (square / 8) as u8) as char;
+
+    format!("{}{}", file, rank)
+}
+
+pub fn parse_square(name: &str) -> Option<Square> {
+    match name.as_bytes() {
+        &[file @ b'a'..=b'h', rank @ b'1'..=b'8'] => {
+            Some((rank - b'1') as Square * 8 + (file - b'a') as Square)
+        }
+        _ => None,
+    }
+}
+
+/// The square `(files, ranks)` away from `square`, unless that is off the
+/// board.
+fn offset(square: Square, (files, ranks): (i32, i32)) -> Option<Square> {
+    let file = (square % 8) as i32 + files;
+    let rank = (square / 8) as i32 + ranks;
+
+    ((0..8).contains(&file) && (0..8).contains(&rank)).then_some((rank * 8 + file) as Square)
+}
+
+const KNIGHT: [(i32, i32); 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+const KING: [(i32, i32); 8] = [
+    (0, 1),
+    (1, 1),
+    (1, 0),
+    (1, -1),
+    (0, -1),
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+];
+const STRAIGHT: [(i32, i32); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+const DIAGONAL: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+
+/// The rank direction pawns of `side` move in.
+fn forward(side: Side) -> i32 {
+    match side {
+        Side::White => 1,
+        Side::Black => -1,
+    }
+}
+
+/// A move in the coordinate notation `move_piece` takes, e.g. `e2e4` or
+/// `e7e8q`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<Kind>,
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", square_name(self.from), square_name(self.to))?;
+
+        match self.promotion {
+            Some(kind) => write!(f, "{}", kind.letter().to_ascii_lowercase()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Move {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid move {:?}, expected e.g. e2e4", s);
+
+        let (Some(from), Some(to)) = (
+            s.get(0..2).and_then(parse_square),
+            s.get(2..4).and_then(parse_square),
+        ) else {
+            return Err(invalid());
+        };
+
+        let promotion = match &s[4..] {
+            "" => None,
+            letter => Some(
+                letter
+                    .parse::<char>()
+                    .ok()
+                    .and_then(Kind::from_letter)
+                    .filter(|kind| !matches!(kind, Kind::Pawn | Kind::King))
+                    .ok_or_else(invalid)?,
+            ),
+        };
+
+        Ok(Move {
+            from,
+            to,
+            promotion,
+        })
+    }
+}
+
+/// Lists `options` as `a, b or c`.
+fn alternatives(options: &[String]) -> String {
+    match options {
+        [rest @ .., last] if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => options.join(""),
+    }
+}
+
+/// The FEN of the standard starting position.
+pub const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// Castling rights, indexed by `Side as usize * 2`, plus one for the queen
+/// side.
+const KING_SIDE: usize = 0;
+const QUEEN_SIDE: usize = 1;
+
+/// The squares the rooks start on, in the order of the castling rights.
+const ROOK_CORNERS: [Square; 4] = [7, 0, 63, 56];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    squares: [Option<Piece>; 64],
+    turn: Side,
+    castling: [bool; 4],
+    en_passant: Option<Square>,
+    /// Half-moves since the last capture or pawn move.
+    pub halfmove: u32,
+    pub fullmove: u32,
+}
+
+impl Position {
+    /// The position on `board`.
+    pub fn of(board: &Board) -> Position {
+        board.to_fen().parse().expect("chess-lib writes valid FEN")
+    }
+
+    pub fn turn(&self) -> Side {
+        self.turn
+    }
+
+    /// Whether this is the standard starting position.
+    pub fn is_start(&self) -> bool {
+        *self == START.parse().unwrap()
+    }
+
+    pub fn piece_at(&self, square: Square) -> Option<Piece> {
+        self.squares[square]
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = (Square, Piece)> + '_ {
+        self.squares
+            .iter()
+            .enumerate()
+            .filter_map(|(square, piece)| piece.map(|piece| (square, piece)))
+    }
+
+    pub fn pawns(&self) -> impl Iterator<Item = (Square, Piece)> + '_ {
+        self.pieces().filter(|(_, piece)| piece.kind == Kind::Pawn)
+    }
+
+    pub fn king(&self, side: Side) -> Option<Square> {
+        self.pieces()
+            .find(|(_, piece)| piece.side == side && piece.kind == Kind::King)
+            .map(|(square, _)| square)
+    }
+
+    pub fn in_check(&self) -> bool {
+        self.king(self.turn)
+            .is_some_and(|king| self.attacked(king, self.turn.opponent()))
+    }
+
+    /// Whether `mv` is a pawn reaching the last rank that does not say what
+    /// it promotes to yet.
+    pub fn needs_promotion(&self, mv: Move) -> bool {
+        mv.promotion.is_none()
+            && (mv.to / 8 == 0 || mv.to / 8 == 7)
+            && self.squares[mv.from].is_some_and(|piece| piece.kind == Kind::Pawn)
+    }
+
+    /// Whether neither side has the material left to ever checkmate: bare
+    /// kings, a single minor piece, or bishops that all move on one color.
+    pub fn insufficient_material(&self) -> bool {
+        let color = |square: Square| (square / 8 + square % 8) % 2;
+        let mut minors = Vec::new();
+
+        for (square, piece) in self.pieces() {
+            match piece.kind {
+                Kind::King => {}
+                Kind::Knight | Kind::Bishop => minors.push((piece.kind, square)),
+                _ => return false,
+            }
+        }
+
+        match minors[..] {
+            [] | [_] => true,
+            [(_, first), ..] => minors
+                .iter()
+                .all(|&(kind, square)| kind == Kind::Bishop && color(square) == color(first)),
+        }
+    }
+
+    /// A hash that two positions share when they count as the same for
+    /// repetitions: the move counters do not matter, and an en passant
+    /// square only does when the capture can be played.
+    pub fn repetition_hash(&self) -> u64 {
+        let en_passant = self.en_passant.filter(|&square| {
+            self.legal_moves()
+                .iter()
+                .any(|&mv| mv.to == square && self.is_en_passant(mv))
+        });
+
+        let mut hasher = DefaultHasher::new();
+        (self.squares, self.turn, self.castling, en_passant).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn is_capture(&self, mv: Move) -> bool {
+        self.squares[mv.to].is_some() || self.is_en_passant(mv)
+    }
+
+    fn is_en_passant(&self, mv: Move) -> bool {
+        Some(mv.to) == self.en_passant
+            && self.squares[mv.to].is_none()
+            && self.squares[mv.from].map(|piece| piece.kind) == Some(Kind::Pawn)
+            && mv.from % 8 != mv.to % 8
+    }
+
+    /// Whether a piece of `by` attacks `square`.
+    pub fn attacked(&self, square: Square, by: Side) -> bool {
+        let holds = |target: Option<Square>, kinds: &[Kind]| {
+            target
+                .and_then(|target| self.squares[target])
+                .is_some_and(|piece| piece.side == by && kinds.contains(&piece.kind))
+        };
+
+        // pawns attack diagonally forward, so look diagonally backward
+        let behind = -forward(by);
+        if holds(offset(square, (-1, behind)), &[Kind::Pawn])
+            || holds(offset(square, (1, behind)), &[Kind::Pawn])
+        {
+            return true;
+        }
+
+        if KNIGHT
+            .iter()
+            .any(|&step| holds(offset(square, step), &[Kind::Knight]))
+            || KING
+                .iter()
+                .any(|&step| holds(offset(square, step), &[Kind::King]))
+        {
+            return true;
+        }
+
+        let sliders = [
+            (STRAIGHT, [Kind::Rook, Kind::Queen]),
+            (DIAGONAL, [Kind::Bishop, Kind::Queen]),
+        ];
+
+        sliders.iter().any(|(directions, kinds)| {
+            directions.iter().any(|&direction| {
+                let mut target = offset(square, direction);
+
+                while let Some(square) = target {
+                    if self.squares[square].is_some() {
+                        return holds(target, kinds);
+                    }
+
+                    target = offset(square, direction);
+                }
+
+                false
+            })
+        })
+    }
+
+    /// Every move the side to move can play.
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let mut moves = self.pseudo_legal_moves();
+
+        moves.retain(|&mv| {
+            let mut next = self.clone();
+            next.play(mv);
+
+            next.king(self.turn)
+                .is_none_or(|king| !next.attacked(king, self.turn.opponent()))
+        });
+
+        moves
+    }
+
+    /// The moves that follow the movement rules of the pieces, but may leave
+    /// the own king in check.
+    fn pseudo_legal_moves(&self) -> Vec<Move> {
+        let mut moves = Vec::new();
+
+        for (from, piece) in self.pieces().filter(|(_, piece)| piece.side == self.turn) {
+            match piece.kind {
+                Kind::Pawn => self.pawn_moves(from, &mut moves),
+                Kind::Knight => self.steps(from, &KNIGHT, &mut moves),
+                Kind::Bishop => self.slides(from, &DIAGONAL, &mut moves),
+                Kind::Rook => self.slides(from, &STRAIGHT, &mut moves),
+                Kind::Queen => {
+                    self.slides(from, &STRAIGHT, &mut moves);
+                    self.slides(from, &DIAGONAL, &mut moves);
+                }
+                Kind::King => {
+                    self.steps(from, &KING, &mut moves);
+                    self.castling_moves(from, &mut moves);
+                }
+            }
+        }
+
+        moves
+    }
+
+    /// Whether a piece of the side to move could go to `square`, and
+    /// whether that would be a capture.
+    fn target(&self, square: Square) -> Option<bool> {
+        match self.squares[square] {
+            None => Some(false),
+            Some(piece) if piece.side != self.turn => Some(true),
+            Some(_) => None,
+        }
+    }
+
+    fn steps(&self, from: Square, steps: &[(i32, i32)], moves: &mut Vec<Move>) {
+        for &step in steps {
+            if let Some(to) = offset(from, step).filter(|&to| self.target(to).is_some()) {
+                moves.push(Move {
+                    from,
+                    to,
+                    promotion: None,
+                });
+            }
+        }
+    }
+
+    fn slides(&self, from: Square, directions: &[(i32, i32)], moves: &mut Vec<Move>) {
+        for &direction in directions {
+            let mut to = offset(from, direction);
+
+            while let Some(square) = to {
+                let Some(capture) = self.target(square) else {
+                    break;
+                };
+
+                moves.push(Move {
+                    from,
+                    to: square,
+                    promotion: None,
+                });
+
+                if capture {
+                    break;
+                }
+
+                to = offset(square, direction);
+            }
+        }
+    }
+
+    fn pawn_moves(&self, from: Square, moves: &mut Vec<Move>) {
+        let forward = forward(self.turn);
+        let start = if self.turn == Side::White { 1 } else { 6 };
+
+        let mut push = |to: Square| {
+            if to / 8 == 0 || to / 8 == 7 {
+                for kind in [Kind::Queen, Kind::Rook, Kind::Bishop, Kind::Knight] {
+                    moves.push(Move {
+                        from,
+                        to,
+                        promotion: Some(kind),
+                    });
+                }
+            } else {
+                moves.push(Move {
+                    from,
+                    to,
+                    promotion: None,
+                });
+            }
+        };
+
+        if let Some(one) = offset(from, (0, forward)).filter(|&to| self.squares[to].is_none()) {
+            push(one);
+
+            if from / 8 == start {
+                if let Some(two) =
+                    offset(one, (0, forward)).filter(|&to| self.squares[to].is_none())
+                {
+                    push(two);
+                }
+            }
+        }
+
+        for files in [-1, 1] {
+            if let Some(to) = offset(from, (files, forward)) {
+                if self.target(to) == Some(true) || Some(to) == self.en_passant {
+                    push(to);
+                }
+            }
+        }
+    }
+
+    fn castling_moves(&self, king: Square, moves: &mut Vec<Move>) {
+        let rights = self.turn as usize * 2;
+        let home = if self.turn == Side::White { 4 } else { 60 };
+        let enemy = self.turn.opponent();
+
+        if king != home || self.attacked(king, enemy) {
+            return;
+        }
+
+        let rook = Some(Piece {
+            side: self.turn,
+            kind: Kind::Rook,
+        });
+
+        // the king may not pass through an attacked square, the square it
+        // lands on is checked like for any other move
+        if self.castling[rights + KING_SIDE]
+            && self.squares[ROOK_CORNERS[rights + KING_SIDE]] == rook
+            && (king + 1..king + 3).all(|square| self.squares[square].is_none())
+            && !self.attacked(king + 1, enemy)
+        {
+            moves.push(Move {
+                from: king,
+                to: king + 2,
+                promotion: None,
+            });
+        }
+
+        if self.castling[rights + QUEEN_SIDE]
+            && self.squares[ROOK_CORNERS[rights + QUEEN_SIDE]] == rook
+            && (king - 3..king).all(|square| self.squares[square].is_none())
+            && !self.attacked(king - 1, enemy)
+        {
+            moves.push(Move {
+                from: king,
+                to: king - 2,
+                promotion: None,
+            });
+        }
+    }
+
+    /// `mv` in Standard Algebraic Notation, e.g. `Nbd7`, `exd5`, `O-O` or
+    /// `e8=Q+`. The move is assumed to be legal.
+    pub fn san(&self, mv: Move) -> String {
+        let Some(piece) = self.squares[mv.from] else {
+            return mv.to_string();
+        };
+
+        let mut san = String::new();
+
+        if piece.kind == Kind::King && mv.from.abs_diff(mv.to) == 2 {
+            san.push_str(if mv.to > mv.from { "O-O" } else { "O-O-O" });
+        } else {
+            let capture = self.is_capture(mv);
+
+            if piece.kind == Kind::Pawn {
+                if capture {
+                    san.push(square_name(mv.from).remove(0));
+                }
+            } else {
+                san.push(piece.kind.letter());
+
+                // name just enough of the origin to tell it from other
+                // pieces of the same kind that could go there as well
+                let rivals: Vec<Square> = self
+                    .legal_moves()
+                    .into_iter()
+                    .filter(|other| other.to == mv.to && other.from != mv.from)
+                    .filter(|other| self.squares[other.from] == Some(piece))
+                    .map(|other| other.from)
+                    .collect();
+
+                let from = square_name(mv.from);
+
+                if rivals.iter().all(|rival| rival % 8 != mv.from % 8) {
+                    if !rivals.is_empty() {
+                        san.push_str(&from[..1]);
+                    }
+                } else if rivals.iter().all(|rival| rival / 8 != mv.from / 8) {
+                    san.push_str(&from[1..]);
+                } else {
+                    san.push_str(&from);
+                }
+            }
+
+            if capture {
+                san.push('x');
+            }
+
+            san.push_str(&square_name(mv.to));
+
+            if let Some(kind) = mv.promotion {
+                san.push('=');
+                san.push(kind.letter());
+            }
+        }
+
+        let mut next = self.clone();
+        next.play(mv);
+
+        if next.in_check() {
+            san.push(if next.legal_moves().is_empty() {
+                '#'
+            } else {
+                '+'
+            });
+        }
+
+        san
+    }
+
+    /// The legal move written as `san`, e.g. `Nf3`, `exd5`, `O-O`, `e8=Q` or
+    /// `Rad1`, or why there is none. Check marks and annotations like `!?`
+    /// are ignored. When `san` leaves out the piece a pawn promotes to, the
+    /// move is returned without one, see `needs_promotion`.
+    pub fn parse_san(&self, san: &str) -> Result<Move, String> {
+        let side = self.turn;
+        let text = san.trim_end_matches(['+', '#', '!', '?']);
+
+        // castling is also written with zeros
+        match text {
+            "O-O" | "0-0" => return self.castle(KING_SIDE),
+            "O-O-O" | "0-0-0" => return self.castle(QUEEN_SIDE),
+            _ => {}
+        }
+
+        let invalid = || {
+            format!(
+                "{:?} is not a move, expected e.g. e2e4, e4, Nf3, exd5, O-O or e8=Q",
+                san
+            )
+        };
+
+        // the piece to promote to, after a `=` or right after the square
+        let (body, promotion) = match text.split_once('=') {
+            Some((body, letter)) => (body, Some(letter)),
+            None => match text.char_indices().last() {
+                Some((index, 'N' | 'B' | 'R' | 'Q')) if index > 1 => {
+                    (&text[..index], Some(&text[index..]))
+                }
+                _ => (text, None),
+            },
+        };
+
+        let promotion = match promotion {
+            None => None,
+            Some(letter) => Some(
+                letter
+                    .parse::<char>()
+                    .ok()
+                    .and_then(Kind::from_letter)
+                    .filter(|kind| !matches!(kind, Kind::Pawn | Kind::King))
+                    .ok_or_else(|| {
+                        format!("a pawn cannot promote to {:?}, pick Q, R, B or N", letter)
+                    })?,
+            ),
+        };
+
+        let (kind, rest) = match body.chars().next() {
+            Some(letter @ 'A'..='Z') => {
+                (Kind::from_letter(letter).ok_or_else(invalid)?, &body[1..])
+            }
+            _ => (Kind::Pawn, body),
+        };
+
+        let split = rest.len().checked_sub(2).ok_or_else(invalid)?;
+        let to = rest
+            .get(split..)
+            .and_then(parse_square)
+            .ok_or_else(invalid)?;
+
+        // what is left names the origin of the piece, and may mark a capture
+        let hint = &rest[..split];
+        let (hint, capture) = match hint.strip_suffix('x') {
+            Some(hint) => (hint, true),
+            None => (hint, false),
+        };
+
+        let mut file = None;
+        let mut rank = None;
+
+        for c in hint.chars() {
+            match c {
+                'a'..='h' if file.is_none() && rank.is_none() => {
+                    file = Some(c as usize - 'a' as usize)
+                }
+                '1'..='8' if rank.is_none() => rank = Some(c as usize - '1' as usize),
+                _ => return Err(invalid()),
+            }
+        }
+
+        // pawns only leave their file when capturing, and say where from
+        if kind == Kind::Pawn {
+            if capture && file.is_none() {
+                let captures: Vec<String> = self
+                    .legal_moves()
+                    .into_iter()
+                    .filter(|mv| mv.to == to && mv.from % 8 != to % 8)
+                    .filter(|mv| {
+                        self.squares[mv.from].is_some_and(|piece| piece.kind == Kind::Pawn)
+                    })
+                    .map(|mv| self.san(mv))
+                    .collect();
+
+                return Err(match captures[..] {
+                    [] => format!("no {} pawn can capture on {}", side, square_name(to)),
+                    _ => format!(
+                        "{} does not say which pawn captures, it could be {}",
+                        san,
+                        alternatives(&captures)
+                    ),
+                });
+            }
+
+            file = file.or(Some(to % 8));
+        }
+
+        let square = square_name(to);
+        let name = format!("{} {}", side, kind.name());
+
+        if let Some(piece) = self.squares[to].filter(|piece| piece.side == side) {
+            return Err(format!(
+                "{} is taken by your own {}",
+                square,
+                piece.kind.name()
+            ));
+        }
+
+        // an en passant capture lands on an empty square
+        if capture && self.squares[to].is_none() && Some(to) != self.en_passant {
+            return Err(format!("{} is not a capture, {} is empty", san, square));
+        }
+
+        let candidates: Vec<Move> = self
+            .pseudo_legal_moves()
+            .into_iter()
+            .filter(|mv| mv.to == to)
+            .filter(|mv| self.squares[mv.from].is_some_and(|piece| piece.kind == kind))
+            .filter(|mv| file.is_none_or(|file| mv.from % 8 == file))
+            .filter(|mv| rank.is_none_or(|rank| mv.from / 8 == rank))
+            .collect();
+
+        if candidates.is_empty() {
+            let origin = match (kind, file, rank) {
+                (Kind::Pawn, _, _) | (_, None, None) => String::new(),
+                (_, Some(file), None) => format!(" on the {}-file", (b'a' + file as u8) as char),
+                (_, None, Some(rank)) => format!(" on rank {}", rank + 1),
+                (_, Some(file), Some(rank)) => format!(" on {}", square_name(rank * 8 + file)),
+            };
+
+            return Err(format!("no {}{} can move to {}", name, origin, square));
+        }
+
+        if promotion.is_some() && candidates.iter().all(|mv| mv.promotion.is_none()) {
+            return Err(format!(
+                "only a pawn reaching the last rank can promote, {} does not",
+                san
+            ));
+        }
+
+        // every promotion is as legal as the one to a queen
+        let legal = self.legal_moves();
+        let moves: Vec<Move> = candidates
+            .into_iter()
+            .filter(|mv| mv.promotion == promotion.or(mv.promotion.and(Some(Kind::Queen))))
+            .filter(|mv| legal.contains(mv))
+            .collect();
+
+        match moves[..] {
+            [mv] => Ok(Move { promotion, ..mv }),
+            [] if self.in_check() => Err(format!(
+                "{} does not get the {} king out of check",
+                san, side
+            )),
+            [] => Err(format!("{} would leave the {} king in check", san, side)),
+            _ => {
+                let options: Vec<String> = moves.iter().map(|&mv| self.san(mv)).collect();
+
+                Err(format!(
+                    "{} is ambiguous, it could be {}",
+                    san,
+                    alternatives(&options)
+                ))
+            }
+        }
+    }
+
+    /// The castling move to the side `wing`, or why it is not legal.
+    fn castle(&self, wing: usize) -> Result<Move, String> {
+        let side = self.turn;
+        let name = if wing == KING_SIDE {
+            "kingside"
+        } else {
+            "queenside"
+        };
+
+        let castling = self.legal_moves().into_iter().find(|mv| {
+            self.squares[mv.from].is_some_and(|piece| piece.kind == Kind::King)
+                && match wing {
+                    KING_SIDE => mv.to == mv.from + 2,
+                    _ => mv.to + 2 == mv.from,
+                }
+        });
+
+        match castling {
+            Some(mv) => Ok(mv),
+            None if !self.castling[side as usize * 2 + wing] => {
+                Err(format!("{} can no longer castle {}", side, name))
+            }
+            None if self.in_check() => Err(format!("{} cannot castle out of check", side)),
+            None => Err(format!(
+                "{} cannot castle {}, the squares between king and rook have to be empty and the king may not pass through or land in check",
+                side, name
+            )),
+        }
+    }
+
+    /// Plays `mv`, which is assumed to be legal.
+    pub fn play(&mut self, mv: Move) {
+        let Some(piece) = self.squares[mv.from] else {
+            return;
+        };
+
+        let capture = self.is_capture(mv);
+
+        if self.is_en_passant(mv) {
+            let captured = offset(mv.to, (0, -forward(piece.side))).unwrap();
+            self.squares[captured] = None;
+        }
+
+        if piece.kind == Kind::King && mv.from.abs_diff(mv.to) == 2 {
+            let (rook_from, rook_to) = if mv.to > mv.from {
+                (mv.from + 3, mv.from + 1)
+            } else {
+                (mv.from - 4, mv.from - 1)
+            };
+
+            self.squares[rook_to] = self.squares[rook_from].take();
+        }
+
+        self.en_passant = (piece.kind == Kind::Pawn && mv.from.abs_diff(mv.to) == 16)
+            .then_some((mv.from + mv.to) / 2);
+
+        for (right, corner) in ROOK_CORNERS.into_iter().enumerate() {
+            if mv.from == corner || mv.to == corner {
+                self.castling[right] = false;
+            }
+        }
+
+        if piece.kind == Kind::King {
+            let rights = piece.side as usize * 2;
+            self.castling[rights + KING_SIDE] = false;
+            self.castling[rights + QUEEN_SIDE] = false;
+        }
+
+        self.squares[mv.from] = None;
+        self.squares[mv.to] = Some(Piece {
+            kind: mv.promotion.unwrap_or(piece.kind),
+            ..piece
+        });
+
+        if capture || piece.kind == Kind::Pawn {
+            self.halfmove = 0;
+        } else {
+            self.halfmove += 1;
+        }
+
+        if self.turn == Side::Black {
+            self.fullmove += 1;
+        }
+
+        self.turn = self.turn.opponent();
+    }
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+
+        // the move counters are often left out
+        let (placement, turn, castling, en_passant, halfmove, fullmove) = match fields[..] {
+            [placement, turn, castling, en_passant] => {
+                (placement, turn, castling, en_passant, "0", "1")
+            }
+            [placement, turn, castling, en_passant, halfmove, fullmove] => {
+                (placement, turn, castling, en_passant, halfmove, fullmove)
+            }
+            _ => {
+                return Err(format!(
+                    "a FEN has 6 fields separated by spaces, found {}",
+                    fields.len()
+                ))
+            }
+        };
+
+        let mut squares = [None; 64];
+        let ranks: Vec<&str> = placement.split('/').collect();
+
+        if ranks.len() != 8 {
+            return Err(format!("the board has {} ranks instead of 8", ranks.len()));
+        }
+
+        for (row, pieces) in ranks.iter().enumerate() {
+            let rank = 7 - row;
+            let mut file = 0;
+
+            for letter in pieces.chars() {
+                if let Some(empty) = letter.to_digit(10).filter(|empty| (1..=8).contains(empty)) {
+                    file += empty as usize;
+                    continue;
+                }
+
+                let kind = Kind::from_letter(letter)
+                    .ok_or_else(|| format!("unknown piece {:?} on rank {}", letter, rank + 1))?;
+
+                if file < 8 {
+                    let side = if letter.is_ascii_uppercase() {
+                        Side::White
+                    } else {
+                        Side::Black
+                    };
+
+                    squares[rank * 8 + file] = Some(Piece { side, kind });
+                }
+
+                file += 1;
+            }
+
+            if file != 8 {
+                return Err(format!(
+                    "rank {} has {} squares instead of 8",
+                    rank + 1,
+                    file
+                ));
+            }
+        }
+
+        let turn = match turn {
+            "w" => Side::White,
+            "b" => Side::Black,
+            other => return Err(format!("the side to move is w or b, not {:?}", other)),
+        };
+
+        let mut rights = [false; 4];
+        if castling != "-" {
+            for letter in castling.chars() {
+                let right = "KQkq"
+                    .find(letter)
+                    .ok_or_else(|| format!("invalid castling rights {:?}", castling))?;
+                rights[right] = true;
+            }
+        }
+
+        let en_passant = match en_passant {
+            "-" => None,
+            square => Some(
+                parse_square(square)
+                    .filter(|square| square / 8 == 2 || square / 8 == 5)
+                    .ok_or_else(|| format!("invalid en passant square {:?}", square))?,
+            ),
+        };
+
+        let halfmove = halfmove
+            .parse()
+            .map_err(|_| format!("invalid halfmove clock {:?}", halfmove))?;
+        let fullmove = fullmove
+            .parse()
+            .ok()
+            .filter(|&fullmove| fullmove > 0)
+            .ok_or_else(|| format!("invalid move number {:?}", fullmove))?;
+
+        let position = Position {
+            squares,
+            turn,
+            castling: rights,
+            en_passant,
+            halfmove,
+            fullmove,
+        };
+
+        for side in [Side::White, Side::Black] {
+            let kings = position
+                .pieces()
+                .filter(|(_, piece)| piece.side == side && piece.kind == Kind::King)
+                .count();
+
+            if kings != 1 {
+                return Err(format!("{} has {} kings instead of 1", side, kings));
+            }
+        }
+
+        if position
+            .pieces()
+            .any(|(square, piece)| piece.kind == Kind::Pawn && (square / 8 == 0 || square / 8 == 7))
+        {
+            return Err("pawns cannot stand on the first or last rank".to_string());
+        }
+
+        let waiting = turn.opponent();
+        if position.attacked(position.king(waiting).unwrap(), turn) {
+            return Err(format!("{} is in check but it is not their turn", waiting));
+        }
+
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The number of move sequences of `depth` half-moves from `position`.
+    fn perft(position: &Position, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+
+        position
+            .legal_moves()
+            .into_iter()
+            .map(|mv| {
+                let mut next = position.clone();
+                next.play(mv);
+                perft(&next, depth - 1)
+            })
+            .sum()
+    }
+
+    fn assert_perft(fen: &str, counts: &[u64]) {
+        let position: Position = fen.parse().unwrap();
+
+        for (depth, &count) in (1..).zip(counts) {
+            assert_eq!(perft(&position, depth), count, "{} at depth {}", fen, depth);
+        }
+    }
+
+    #[test]
+    fn perft_start() {
+        assert_perft(START, &[20, 400, 8902]);
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        assert_perft(
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            &[48, 2039, 97862],
+        );
+    }
+
+    #[test]
+    fn perft_en_passant_and_pins() {
+        assert_perft(
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            &[14, 191, 2812, 43238],
+        );
+    }
+
+    #[test]
+    fn perft_promotions() {
+        assert_perft(
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            &[6, 264, 9467],
+        );
+        assert_perft(
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            &[44, 1486, 62379],
+        );
+    }
+
+    /// Plays `san` in `position`, checking it reads back the same.
+    fn round_trip(fen: &str, san: &str) -> Move {
+        let position: Position = fen.parse().unwrap();
+        let mv = position.parse_san(san).unwrap();
+
+        assert_eq!(position.san(mv), san);
+        mv
+    }
+
+    #[test]
+    fn san_round_trips_every_legal_move() {
+        let fens = [
+            START,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "4k3/8/8/1N3N2/8/1N3N2/8/4K3 w - - 0 1",
+        ];
+
+        for fen in fens {
+            let position: Position = fen.parse().unwrap();
+
+            for mv in position.legal_moves() {
+                let san = position.san(mv);
+                assert_eq!(position.parse_san(&san), Ok(mv), "{} in {}", san, fen);
+            }
+        }
+    }
+
+    #[test]
+    fn san_disambiguation() {
+        let knights = "4k3/8/8/1N3N2/8/1N3N2/8/4K3 w - - 0 1";
+
+        assert_eq!(round_trip(knights, "Nb3d4").to_string(), "b3d4");
+        assert_eq!(round_trip(knights, "Nfd6+").to_string(), "f5d6");
+
+        let file = "4k3/8/8/1N6/8/8/8/1N2K3 w - - 0 1";
+        assert_eq!(round_trip(file, "N1c3").to_string(), "b1c3");
+        assert_eq!(round_trip(file, "N5c3").to_string(), "b5c3");
+
+        let position: Position = knights.parse().unwrap();
+        assert!(position.parse_san("Nd4").unwrap_err().contains("ambiguous"));
+    }
+
+    #[test]
+    fn san_promotion() {
+        let fen = "r3k3/1P6/8/8/8/8/8/4K3 w q - 0 1";
+
+        assert_eq!(round_trip(fen, "b8=Q+").to_string(), "b7b8q");
+        assert_eq!(round_trip(fen, "bxa8=N").to_string(), "b7a8n");
+
+        let position: Position = fen.parse().unwrap();
+        assert_eq!(position.parse_san("b8Q").unwrap().to_string(), "b7b8q");
+        assert!(position.parse_san("b8=K").is_err());
+
+        // the piece is asked for when it is missing
+        let mv = position.parse_san("b8").unwrap();
+        assert!(position.needs_promotion(mv));
+    }
+
+    #[test]
+    fn san_castling() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+
+        assert_eq!(round_trip(fen, "O-O").to_string(), "e1g1");
+        assert_eq!(round_trip(fen, "O-O-O").to_string(), "e1c1");
+
+        let position: Position = fen.parse().unwrap();
+        assert_eq!(position.parse_san("0-0").unwrap().to_string(), "e1g1");
+
+        let attacked: Position = "4k3/8/8/8/8/8/8/R3K1rR w KQ - 0 1".parse().unwrap();
+        assert!(attacked.parse_san("O-O").is_err());
+    }
+}
@@ -0,0 +1,957 @@
+//! Wire protocol spoken between `server` and `client`.
+//!
+//! Every message is a single line of UTF-8 text: a type tag, optionally
+//! followed by a space and a payload, terminated by `\n`. For example:
+//!
+//! ```text
+//! MOVE e7e8q
+//! ```
+//!
+//! Unknown tags are reported as errors rather than silently skipped, so both
+//! sides notice when they are not speaking the same protocol.
+//!
+//! A connection starts with a handshake: the joining side sends `HELLO` and
+//! the `POSITION` it would like to start from, the host answers with
+//! `WELCOME` (carrying the color assigned to the joining side, the id of the
+//! game and, from a lobby, the token to resume it with) and the `POSITION`
+//! the game starts from, or with `REJECT` when the versions are
+//! incompatible. A player that lost its connection opens with `RESUME` and
+//! that token instead, and receives the starting `POSITION`, the `MOVES` played so far (and the `CLOCK` of a timed
+//! game) after the `WELCOME`. Spectators open with `SPECTATE` and receive
+//! the `PLAYERS` and the current `POSITION`, followed by every `MOVE` played.
+//!
+//! Every `MOVE` is answered by the receiving side: `ACK` with a checksum of
+//! its board after the move, or `NACK` with the reason the move was refused.
+//! The sender compares the checksum against its own board to detect the two
+//! games drifting apart.
+//!
+//! In timed games the moving side sends its `CLOCK` right before each `MOVE`,
+//! so both sides agree on the time left and on who lost on time.
+//!
+//! Players can `CHAT` at any time, chat messages are also relayed to
+//! spectators.
+//!
+//! On their turn players may also `RESIGN`, or `OFFER` a draw or a takeback,
+//! which the opponent answers with `ACCEPT` or `DECLINE`. Taking back just
+//! their last move may also be offered on the opponent's turn. An offer that
+//! crosses a move or another offer is declined. A lobby sends `WITHDRAW`
+//! when the player who made the offer lost the connection.
+//!
+//! On their turn players may claim a draw by threefold repetition or the
+//! fifty-move rule by sending its `RESULT`.
+//!
+//! Both sides notice a move ending the game on their own boards. The side
+//! accepting such a move still follows its `ACK` with the `RESULT`, and the
+//! lobby sends the `RESULT` to players and spectators alike.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+
+use chess_lib::chess::Board;
+
+use crate::{clock::TimeControl, position::Position, Outcome, Side};
+
+/// Bumped whenever a change to the wire format breaks older peers.
+pub const VERSION: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorPreference {
+    White,
+    Black,
+    Random,
+}
+
+impl ColorPreference {
+    /// Resolves the preferences of both players into the host's side.
+    ///
+    /// A specific wish wins over `random`; when both players want the same
+    /// color (or both don't care) a coin is flipped.
+    pub fn negotiate(host: ColorPreference, guest: ColorPreference) -> Side {
+        use ColorPreference::*;
+
+        match (host, guest) {
+            (White, Black) | (White, Random) | (Random, Black) => Side::White,
+            (Black, White) | (Black, Random) | (Random, White) => Side::Black,
+            _ => {
+                if rand::random() {
+                    Side::White
+                } else {
+                    Side::Black
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ColorPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Something a player can propose to the opponent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offer {
+    Draw,
+    /// Take back the given number of half-moves.
+    Takeback(usize),
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offer::Draw => write!(f, "draw"),
+            Offer::Takeback(plies) => write!(f, "takeback {}", plies),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Sent by the joining side to open the handshake. `code` selects the
+    /// game to join on a lobby server, `None` takes the first free opponent.
+    /// `time` is the preferred time control, if any.
+    Hello {
+        version: u32,
+        color: ColorPreference,
+        code: Option<String>,
+        time: Option<TimeControl>,
+        name: String,
+    },
+    /// The host accepted the handshake; `color` is the side the joining
+    /// player will play in the game with id `game`, under time control
+    /// `time`. Only a lobby server hands out a `token`, with which the
+    /// player can take their seat again after losing the connection.
+    Welcome {
+        version: u32,
+        color: Side,
+        game: u64,
+        time: Option<TimeControl>,
+        token: Option<u64>,
+        name: String,
+    },
+    /// Sent instead of `HELLO` by `name` to take their seat in the game with
+    /// id `game` again after losing the connection, proven by the `token`
+    /// they were welcomed with.
+    Resume {
+        version: u32,
+        game: u64,
+        token: u64,
+        name: String,
+    },
+    /// Every move played in a resumed game so far.
+    Moves(Vec<String>),
+    /// Sent instead of `HELLO` to follow the game with id `game` without
+    /// playing in it.
+    Spectate {
+        version: u32,
+        game: u64,
+    },
+    /// The names of both players of a spectated game.
+    Players {
+        white: String,
+        black: String,
+    },
+    /// A position as FEN: where a game starts during the handshake, the
+    /// current position of a spectated game afterwards.
+    Position(String),
+    /// The host refused the handshake, the connection is closed afterwards.
+    Reject(String),
+    /// A move in coordinate notation, e.g. `e2e4` or `e7e8q`. A promotion
+    /// always names the piece, under-promotions included.
+    Move(String),
+    /// The last move was applied, carrying the [`checksum`] of the board
+    /// afterwards.
+    MoveAccepted(u64),
+    /// The last move was refused and not applied.
+    MoveRejected(String),
+    /// The time left for white and black.
+    Clock {
+        white: Duration,
+        black: Duration,
+    },
+    /// A chat message from the player on `side`.
+    Chat {
+        side: Side,
+        text: String,
+    },
+    /// The player of the given side gives up.
+    Resign(Side),
+    /// Proposes something to the opponent, who has to answer with `Accept`
+    /// or `Decline` before making a move.
+    Offer(Offer),
+    Accept,
+    Decline,
+    /// The offer of the opponent can no longer be answered, because they
+    /// lost the connection to the lobby.
+    Withdraw,
+    /// The game is over.
+    GameOver(Outcome),
+}
+
+impl Message {
+    fn tag(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "HELLO",
+            Message::Welcome { .. } => "WELCOME",
+            Message::Resume { .. } => "RESUME",
+            Message::Moves(_) => "MOVES",
+            Message::Spectate { .. } => "SPECTATE",
+            Message::Players { .. } => "PLAYERS",
+            Message::Position(_) => "POSITION",
+            Message::Reject(_) => "REJECT",
+            Message::Move(_) => "MOVE",
+            Message::MoveAccepted(_) => "ACK",
+            Message::MoveRejected(_) => "NACK",
+            Message::Clock { .. } => "CLOCK",
+            Message::Chat { .. } => "CHAT",
+            Message::Resign(_) => "RESIGN",
+            Message::Offer(_) => "OFFER",
+            Message::Accept => "ACCEPT",
+            Message::Decline => "DECLINE",
+            Message::Withdraw => "WITHDRAW",
+            Message::GameOver(_) => "RESULT",
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tag())?;
+
+        match self {
+            Message::Hello {
+                version,
+                color,
+                code,
+                time,
+                name,
+            } => write!(
+                f,
+                " {} {} {} {} {}",
+                version,
+                color,
+                code.as_deref().unwrap_or("-"),
+                optional(time),
+                name
+            ),
+            Message::Welcome {
+                version,
+                color,
+                game,
+                time,
+                token,
+                name,
+            } => write!(
+                f,
+                " {} {} {} {} {} {}",
+                version,
+                color,
+                game,
+                optional(time),
+                optional(token),
+                name
+            ),
+            Message::Resume {
+                version,
+                game,
+                token,
+                name,
+            } => write!(f, " {} {} {} {}", version, game, token, name),
+            Message::Moves(moves) => write!(f, " {}", moves.join(" ")),
+            Message::Spectate { version, game } => write!(f, " {} {}", version, game),
+            // the white name is length-prefixed, as names may contain spaces
+            Message::Players { white, black } => {
+                write!(f, " {} {}{}", white.chars().count(), white, black)
+            }
+            Message::Position(fen) => write!(f, " {}", fen),
+            Message::Reject(reason) => write!(f, " {}", reason),
+            Message::Move(mv) => write!(f, " {}", mv),
+            Message::MoveAccepted(checksum) => write!(f, " {:016x}", checksum),
+            Message::MoveRejected(reason) => write!(f, " {}", reason),
+            Message::Clock { white, black } => {
+                write!(f, " {} {}", white.as_millis(), black.as_millis())
+            }
+            Message::Chat { side, text } => write!(f, " {} {}", side, text),
+            Message::Resign(side) => write!(f, " {}", side),
+            Message::Offer(offer) => write!(f, " {}", offer),
+            Message::Accept | Message::Decline | Message::Withdraw => Ok(()),
+            Message::GameOver(outcome) => write!(f, " {} {}", outcome.score(), outcome.reason()),
+        }
+    }
+}
+
+impl FromStr for Message {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (tag, payload) = match line.split_once(' ') {
+            Some((tag, payload)) => (tag, payload.trim()),
+            None => (line, ""),
+        };
+
+        match tag {
+            "HELLO" => {
+                let (version, color, rest) = parse_greeting(payload)?;
+                let color = ColorPreference::from_str(color, true).map_err(invalid_data)?;
+                let mut rest = rest.splitn(3, ' ');
+                let code = rest.next().unwrap_or("-");
+                let time = parse_optional(rest.next().unwrap_or("-"))?;
+                let name = rest.next().unwrap_or_default();
+
+                Ok(Message::Hello {
+                    version,
+                    color,
+                    code: Some(code).filter(|&code| code != "-").map(String::from),
+                    time,
+                    name: name.trim().to_string(),
+                })
+            }
+            "WELCOME" => {
+                let (version, color, rest) = parse_greeting(payload)?;
+                let color = Side::from_str(color, true).map_err(invalid_data)?;
+                let mut rest = rest.splitn(4, ' ');
+                let game = parse_number(rest.next().unwrap_or_default())?;
+                let time = parse_optional(rest.next().unwrap_or("-"))?;
+                let token = parse_optional(rest.next().unwrap_or("-"))?;
+                let name = rest.next().unwrap_or_default();
+
+                Ok(Message::Welcome {
+                    version,
+                    color,
+                    game,
+                    time,
+                    token,
+                    name: name.trim().to_string(),
+                })
+            }
+            "RESUME" => {
+                let mut parts = payload.splitn(4, ' ');
+                let version = parse_number(parts.next().unwrap_or_default())?;
+                let game = parse_number(parts.next().unwrap_or_default())?;
+                let token = parse_number(parts.next().unwrap_or_default())?;
+                let name = parts.next().unwrap_or_default();
+
+                Ok(Message::Resume {
+                    version,
+                    game,
+                    token,
+                    name: name.trim().to_string(),
+                })
+            }
+            "MOVES" => Ok(Message::Moves(
+                payload.split_whitespace().map(String::from).collect(),
+            )),
+            "SPECTATE" => {
+                let (version, game) = payload.split_once(' ').unwrap_or((payload, ""));
+
+                Ok(Message::Spectate {
+                    version: parse_number(version)?,
+                    game: parse_number(game)?,
+                })
+            }
+            "PLAYERS" => {
+                let (length, names) = payload.split_once(' ').unwrap_or((payload, ""));
+                let length: usize = parse_number(length)?;
+                let split = names
+                    .char_indices()
+                    .nth(length)
+                    .map_or(names.len(), |(index, _)| index);
+
+                Ok(Message::Players {
+                    white: names[..split].to_string(),
+                    black: names[split..].to_string(),
+                })
+            }
+            "POSITION" if !payload.is_empty() => Ok(Message::Position(payload.to_string())),
+            "REJECT" => Ok(Message::Reject(payload.to_string())),
+            "MOVE" if !payload.is_empty() => Ok(Message::Move(payload.to_string())),
+            "ACK" => u64::from_str_radix(payload, 16)
+                .map(Message::MoveAccepted)
+                .map_err(|_| invalid_data(format!("invalid checksum: {:?}", payload))),
+            "NACK" => Ok(Message::MoveRejected(payload.to_string())),
+            "CLOCK" => {
+                let (white, black) = payload.split_once(' ').unwrap_or((payload, ""));
+
+                Ok(Message::Clock {
+                    white: Duration::from_millis(parse_number(white)?),
+                    black: Duration::from_millis(parse_number(black)?),
+                })
+            }
+            "CHAT" => {
+                let (side, text) = payload.split_once(' ').unwrap_or((payload, ""));
+
+                Ok(Message::Chat {
+                    side: Side::from_str(side, true).map_err(invalid_data)?,
+                    text: text.to_string(),
+                })
+            }
+            "RESIGN" => Side::from_str(payload, true)
+                .map(Message::Resign)
+                .map_err(invalid_data),
+            "OFFER" => match payload.split_once(' ').unwrap_or((payload, "")) {
+                ("draw", _) => Ok(Message::Offer(Offer::Draw)),
+                ("takeback", plies) => Ok(Message::Offer(Offer::Takeback(parse_number(plies)?))),
+                _ => Err(invalid_data(format!("unknown offer: {:?}", payload))),
+            },
+            "ACCEPT" => Ok(Message::Accept),
+            "DECLINE" => Ok(Message::Decline),
+            "WITHDRAW" => Ok(Message::Withdraw),
+            "RESULT" => {
+                let (score, reason) = payload.split_once(' ').unwrap_or((payload, ""));
+                let reason = reason.to_string();
+
+                match score {
+                    "1-0" => Ok(Message::GameOver(Outcome::Win(Side::White, reason))),
+                    "0-1" => Ok(Message::GameOver(Outcome::Win(Side::Black, reason))),
+                    "1/2-1/2" => Ok(Message::GameOver(Outcome::Draw(reason))),
+                    _ => Err(invalid_data(format!("invalid result: {:?}", payload))),
+                }
+            }
+            _ => Err(invalid_data(format!("unexpected message: {:?}", line))),
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(number: &str) -> io::Result<T> {
+    number
+        .parse()
+        .map_err(|_| invalid_data(format!("invalid number: {:?}", number)))
+}
+
+/// Formats an optional field, `-` stands for `None`.
+fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn parse_optional<T: FromStr>(value: &str) -> io::Result<Option<T>> {
+    match value {
+        "-" => Ok(None),
+        value => value
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid_data(format!("invalid value: {:?}", value))),
+    }
+}
+
+/// Splits a `<version> <color> <rest>` payload, the rest may contain spaces.
+fn parse_greeting(payload: &str) -> io::Result<(u32, &str, &str)> {
+    let mut parts = payload.splitn(3, ' ');
+
+    let version = parse_number(parts.next().unwrap_or_default())?;
+    let color = parts
+        .next()
+        .ok_or_else(|| invalid_data(format!("missing color in {:?}", payload)))?;
+    let rest = parts.next().unwrap_or_default();
+
+    Ok((version, color, rest))
+}
+
+/// A framed, bidirectional connection to the other player.
+pub struct Connection {
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        let writer = Writer(stream.try_clone()?);
+
+        Ok(Connection {
+            reader: Reader(BufReader::new(stream)),
+            writer,
+        })
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.writer.send(message)
+    }
+
+    /// Blocks until a complete message has been received.
+    pub fn receive(&mut self) -> io::Result<Message> {
+        self.reader.receive()
+    }
+
+    /// Whether the other end has closed the connection, checked without
+    /// waiting and without consuming anything it sent.
+    pub fn closed(&self) -> bool {
+        if !self.reader.0.buffer().is_empty() {
+            return false;
+        }
+
+        let stream = self.reader.0.get_ref();
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+
+        let closed = match stream.peek(&mut [0]) {
+            Ok(read) => read == 0,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        };
+
+        stream.set_nonblocking(false).is_err() || closed
+    }
+
+    /// Splits the connection, so both directions can be used from different
+    /// threads.
+    pub fn split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+/// The receiving half of a [`Connection`].
+pub struct Reader(BufReader<TcpStream>);
+
+impl Reader {
+    /// Blocks until a complete message has been received.
+    pub fn receive(&mut self) -> io::Result<Message> {
+        let mut line = String::new();
+
+        if self.0.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by peer",
+            ));
+        }
+
+        line.trim_end_matches(['\r', '\n']).parse()
+    }
+}
+
+/// The sending half of a [`Connection`].
+pub struct Writer(TcpStream);
+
+impl Writer {
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        // a payload must never contain the frame delimiter
+        let line = message.to_string().replace(['\r', '\n'], " ");
+
+        self.0.write_all(line.as_bytes())?;
+        self.0.write_all(b"\n")?;
+        self.0.flush()
+    }
+
+    /// Closes both directions of the connection, which also wakes up a
+    /// thread blocked on the matching [`Reader`].
+    pub fn close(&self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+/// Joins a game hosted on the other end of `connection`.
+///
+/// Returns the terms of the game we were assigned.
+pub fn join(
+    connection: &mut Connection,
+    name: &str,
+    color: ColorPreference,
+    code: Option<&str>,
+    time: Option<TimeControl>,
+    start: &str,
+) -> io::Result<Terms> {
+    connection.send(&Message::Hello {
+        version: VERSION,
+        color,
+        code: code.map(String::from),
+        time,
+        name: name.to_string(),
+    })?;
+    connection.send(&Message::Position(start.to_string()))?;
+
+    welcomed(connection)
+}
+
+/// Takes our seat in the game with id `game` again, after losing the
+/// connection to it, with the `token` we were welcomed with.
+///
+/// Returns the terms of the game, including the moves played so far.
+pub fn resume(connection: &mut Connection, name: &str, game: u64, token: u64) -> io::Result<Terms> {
+    connection.send(&Message::Resume {
+        version: VERSION,
+        game,
+        token,
+        name: name.to_string(),
+    })?;
+
+    let mut terms = welcomed(connection)?;
+
+    terms.moves = match connection.receive()? {
+        Message::Moves(moves) => moves,
+        other => return Err(unexpected(&other)),
+    };
+
+    if terms.time.is_some() {
+        terms.clock = match connection.receive()? {
+            Message::Clock { white, black } => Some((white, black)),
+            other => return Err(unexpected(&other)),
+        };
+    }
+
+    Ok(terms)
+}
+
+/// Waits for the host to answer the opening of a handshake.
+fn welcomed(connection: &mut Connection) -> io::Result<Terms> {
+    let (side, game, time, token, opponent) = match connection.receive()? {
+        Message::Welcome {
+            version,
+            color,
+            game,
+            time,
+            token,
+            name,
+        } if version == VERSION => (color, game, time, token, name),
+        Message::Welcome { version, .. } => return Err(incompatible(version)),
+        Message::Reject(reason) => {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+        }
+        other => return Err(unexpected(&other)),
+    };
+
+    Ok(Terms {
+        side,
+        game,
+        time,
+        token,
+        opponent,
+        start: starting_position(connection)?,
+        moves: Vec::new(),
+        clock: None,
+    })
+}
+
+/// Receives the `POSITION` of a handshake, refusing invalid FEN.
+fn starting_position(connection: &mut Connection) -> io::Result<String> {
+    match connection.receive()? {
+        Message::Position(fen) => match fen.parse::<Position>() {
+            Ok(_) => Ok(fen),
+            Err(e) => Err(invalid_data(format!("invalid FEN {:?}: {}", fen, e))),
+        },
+        other => Err(unexpected(&other)),
+    }
+}
+
+/// The position a game starts from: `preferred` unless that is just the
+/// standard one, in which case `other` gets to pick.
+pub fn pick_start(preferred: &str, other: &str) -> String {
+    let standard = preferred
+        .parse::<Position>()
+        .is_ok_and(|position| position.is_start());
+
+    if standard { other } else { preferred }.to_string()
+}
+
+/// What both players agreed on during the handshake.
+pub struct Terms {
+    /// The side of the local player.
+    pub side: Side,
+    pub game: u64,
+    pub time: Option<TimeControl>,
+    /// The token to resume the game with after losing the connection, only
+    /// lobby games can be resumed.
+    pub token: Option<u64>,
+    pub opponent: String,
+    /// The FEN of the position the game starts from.
+    pub start: String,
+    /// The moves already played, when resuming a game.
+    pub moves: Vec<String>,
+    /// The time left for white and black, when resuming a timed game.
+    pub clock: Option<(Duration, Duration)>,
+}
+
+/// A player that opened the handshake with a compatible `HELLO`.
+pub struct Guest {
+    pub name: String,
+    pub color: ColorPreference,
+    pub code: Option<String>,
+    pub time: Option<TimeControl>,
+    /// The FEN of the position the guest would like to start from.
+    pub start: String,
+}
+
+/// Starts following the game with id `game`.
+///
+/// Returns the names of the white and black player and the current position.
+pub fn spectate(connection: &mut Connection, game: u64) -> io::Result<(String, String, String)> {
+    connection.send(&Message::Spectate {
+        version: VERSION,
+        game,
+    })?;
+
+    let (white, black) = match connection.receive()? {
+        Message::Players { white, black } => (white, black),
+        Message::Reject(reason) => {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+        }
+        other => return Err(unexpected(&other)),
+    };
+
+    match connection.receive()? {
+        Message::Position(fen) => Ok((white, black, fen)),
+        other => Err(unexpected(&other)),
+    }
+}
+
+/// The opening of a handshake with a compatible version.
+pub enum Greeting {
+    Play(Guest),
+    /// A player wants to resume the game with id `game`, with the `token`
+    /// of their seat.
+    Resume {
+        game: u64,
+        token: u64,
+    },
+    Spectate(u64),
+}
+
+/// Receives the `HELLO`, `RESUME` or `SPECTATE` of a new connection,
+/// rejecting incompatible versions.
+pub fn greet(connection: &mut Connection) -> io::Result<Greeting> {
+    let version = match connection.receive()? {
+        Message::Hello {
+            version,
+            color,
+            code,
+            time,
+            name,
+        } if version == VERSION => {
+            return Ok(Greeting::Play(Guest {
+                name,
+                color,
+                code,
+                time,
+                start: starting_position(connection)?,
+            }))
+        }
+        Message::Resume {
+            version,
+            game,
+            token,
+            ..
+        } if version == VERSION => return Ok(Greeting::Resume { game, token }),
+        Message::Spectate { version, game } if version == VERSION => {
+            return Ok(Greeting::Spectate(game))
+        }
+        Message::Hello { version, .. }
+        | Message::Resume { version, .. }
+        | Message::Spectate { version, .. } => version,
+        other => return Err(unexpected(&other)),
+    };
+
+    let error = incompatible(version);
+    connection.send(&Message::Reject(error.to_string()))?;
+    Err(error)
+}
+
+/// Accepts a player joining the game with id `game` we host. Our own time
+/// control and starting position take precedence over the ones the guest
+/// would like.
+pub fn accept(
+    connection: &mut Connection,
+    name: &str,
+    color: ColorPreference,
+    game: u64,
+    time: Option<TimeControl>,
+    start: &str,
+) -> io::Result<Terms> {
+    let reason = match greet(connection)? {
+        Greeting::Play(guest) => return welcome(connection, guest, name, color, game, time, start),
+        Greeting::Resume { .. } => "only lobby servers can resume games",
+        Greeting::Spectate(_) => "this host does not accept spectators",
+    };
+
+    connection.send(&Message::Reject(reason.to_string()))?;
+    Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+}
+
+fn welcome(
+    connection: &mut Connection,
+    guest: Guest,
+    name: &str,
+    color: ColorPreference,
+    game: u64,
+    time: Option<TimeControl>,
+    start: &str,
+) -> io::Result<Terms> {
+    let side = ColorPreference::negotiate(color, guest.color);
+    let time = time.or(guest.time);
+    let start = pick_start(start, &guest.start);
+
+    connection.send(&Message::Welcome {
+        version: VERSION,
+        color: side.opponent(),
+        game,
+        time,
+        token: None,
+        name: name.to_string(),
+    })?;
+    connection.send(&Message::Position(start.clone()))?;
+
+    Ok(Terms {
+        side,
+        game,
+        time,
+        token: None,
+        opponent: guest.name,
+        start,
+        moves: Vec::new(),
+        clock: None,
+    })
+}
+
+/// A checksum of the position on `board`, exchanged after every move.
+///
+/// This is a 64-bit FNV-1a hash of the FEN, which unlike the std hasher is
+/// guaranteed to be the same on every build of both peers.
+pub fn checksum(board: &Board) -> u64 {
+    board
+        .to_fen()
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// The error for a message that is valid, but not expected at this point.
+pub fn unexpected(message: &Message) -> io::Error {
+    invalid_data(format!("unexpected {} message", message.tag()))
+}
+
+fn incompatible(version: u32) -> io::Error {
+    invalid_data(format!(
+        "incompatible protocol version {} (this build speaks version {})",
+        version, VERSION
+    ))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let time = "5+3".parse().ok();
+
+        let messages = [
+            Message::Hello {
+                version: VERSION,
+                color: ColorPreference::Random,
+                code: Some("team-a".to_string()),
+                time,
+                name: "Ada Lovelace".to_string(),
+            },
+            Message::Hello {
+                version: VERSION,
+                color: ColorPreference::White,
+                code: None,
+                time: None,
+                name: "bob".to_string(),
+            },
+            Message::Welcome {
+                version: VERSION,
+                color: Side::Black,
+                game: 7,
+                time,
+                token: Some(u64::MAX),
+                name: "Charles Babbage".to_string(),
+            },
+            Message::Welcome {
+                version: VERSION,
+                color: Side::White,
+                game: 8,
+                time: None,
+                token: None,
+                name: "bob".to_string(),
+            },
+            Message::Resume {
+                version: VERSION,
+                game: 7,
+                token: u64::MAX,
+                name: "Ada Lovelace".to_string(),
+            },
+            Message::Moves(Vec::new()),
+            Message::Moves(vec!["e2e4".to_string(), "e7e8q".to_string()]),
+            Message::Spectate {
+                version: VERSION,
+                game: 3,
+            },
+            Message::Players {
+                white: "Ada Lovelace".to_string(),
+                black: "Charles Babbage".to_string(),
+            },
+            Message::Position(crate::position::START.to_string()),
+            Message::Reject("the game is full".to_string()),
+            Message::Move("e7e8n".to_string()),
+            Message::MoveAccepted(u64::MAX),
+            Message::MoveRejected("it is not your turn".to_string()),
+            Message::Clock {
+                white: Duration::from_millis(299_500),
+                black: Duration::ZERO,
+            },
+            Message::Chat {
+                side: Side::White,
+                text: "good luck, have fun".to_string(),
+            },
+            Message::Resign(Side::Black),
+            Message::Offer(Offer::Draw),
+            Message::Offer(Offer::Takeback(2)),
+            Message::Accept,
+            Message::Decline,
+            Message::Withdraw,
+            Message::GameOver(Outcome::Win(Side::White, "black is checkmated".to_string())),
+            Message::GameOver(Outcome::Draw("threefold repetition".to_string())),
+        ];
+
+        for message in messages {
+            let line = message.to_string();
+            assert_eq!(line.parse::<Message>().ok(), Some(message), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        for line in [
+            "",
+            "NONSENSE",
+            "MOVE",
+            "ACK forty",
+            "OFFER nothing",
+            "RESULT 2-0 no one won",
+            "HELLO 15 white - 1e300+0 bob",
+            "RESUME 15 7 Ada Lovelace",
+        ] {
+            assert!(line.parse::<Message>().is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn notices_a_closed_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connection = Connection::new(listener.accept().unwrap().0).unwrap();
+
+        assert!(!connection.closed());
+
+        // what the peer sent is left to read
+        peer.write_all(b"ACCEPT\n").unwrap();
+        drop(peer);
+        assert!(!connection.closed());
+
+        assert_eq!(connection.receive().ok(), Some(Message::Accept));
+        assert!(connection.closed());
+    }
+}
@@ -0,0 +1,404 @@
+//! The Universal Chess Interface, so chess GUIs and tools can drive the
+//! built-in engine, and the built-in engine can be swapped for another one.
+//!
+//! As an engine, commands are read from stdin and answered on stdout, one
+//! per line. A `go` searches on its own thread, so `isready` and `stop` are
+//! answered while it runs. Unknown commands are ignored, as the protocol
+//! asks.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use chess_lib::chess::{Board, Error};
+
+use crate::{
+    board_from_fen,
+    engine::{self, Info, Limits, Score, LEVELS},
+    position::{Move, Position},
+    Side,
+};
+
+/// Deep enough to only be stopped by time or `stop`.
+const MAX_DEPTH: u32 = 64;
+
+/// A search running on its own thread, and the flag that stops it.
+type Running = (Arc<AtomicBool>, JoinHandle<()>);
+
+pub fn run() -> Result<(), Error> {
+    let mut board = Board::default_board()?;
+    let mut level = *LEVELS.end();
+    let mut running: Option<Running> = None;
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("uci") => {
+                println!("id name chess-cli {}", env!("CARGO_PKG_VERSION"));
+                println!(
+                    "option name Skill Level type spin default {} min {} max {}",
+                    LEVELS.end(),
+                    LEVELS.start(),
+                    LEVELS.end()
+                );
+                println!("uciok");
+            }
+
+            Some("isready") => println!("readyok"),
+
+            Some("setoption") => {
+                let option = line.split_once("name").map(|(_, option)| option.trim());
+
+                if let Some(value) = option.and_then(|option| option.strip_prefix("Skill Level")) {
+                    match value
+                        .trim()
+                        .strip_prefix("value")
+                        .map(|value| value.trim().parse())
+                    {
+                        Some(Ok(value)) if LEVELS.contains(&value) => level = value,
+                        _ => println!("info string invalid skill level {:?}", value.trim()),
+                    }
+                }
+            }
+
+            Some("ucinewgame") => {
+                stop(&mut running);
+                board = Board::default_board()?;
+            }
+
+            Some("position") => {
+                stop(&mut running);
+
+                match position(words) {
+                    Ok(position) => board = position,
+                    Err(e) => println!("info string {}", e),
+                }
+            }
+
+            Some("go") => {
+                stop(&mut running);
+                running = Some(go(Position::of(&board), words, level));
+            }
+
+            Some("stop") => stop(&mut running),
+
+            Some("quit") => break,
+
+            _ => {}
+        }
+    }
+
+    stop(&mut running);
+
+    Ok(())
+}
+
+/// Stops the running search, which answers with its best move so far.
+fn stop(running: &mut Option<Running>) {
+    if let Some((stop, search)) = running.take() {
+        stop.store(true, Ordering::Relaxed);
+        let _ = search.join();
+    }
+}
+
+/// Sets up the board of `position [startpos | fen <FEN>] [moves <move>...]`.
+fn position<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Board, String> {
+    let mut board = match words.next() {
+        Some("startpos") => Board::default_board().map_err(|e| e.to_string())?,
+        Some("fen") => {
+            let fen = words
+                .by_ref()
+                .take_while(|&word| word != "moves")
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            board_from_fen(&fen).map_err(|e| format!("invalid FEN {:?}: {}", fen, e))?
+        }
+        _ => return Err("expected startpos or fen after position".to_string()),
+    };
+
+    for mv in words.skip_while(|&word| word == "moves") {
+        board
+            .move_piece(mv)
+            .map_err(|e| format!("illegal move {}: {}", mv, e))?;
+    }
+
+    Ok(board)
+}
+
+/// Starts searching `position` as asked by the arguments of `go`.
+fn go<'a>(position: Position, mut words: impl Iterator<Item = &'a str>, level: u8) -> Running {
+    let mut limits = Limits::level(level);
+    limits.time = None;
+
+    // only weakened levels have a depth of their own
+    if level == *LEVELS.end() {
+        limits.depth = MAX_DEPTH;
+    }
+
+    let side = position.turn();
+    let mut clock = None;
+    let mut increment = Duration::ZERO;
+    let mut moves_to_go = 30;
+    let mut infinite = false;
+
+    while let Some(word) = words.next() {
+        if word == "infinite" {
+            infinite = true;
+            continue;
+        }
+
+        let Some(value) = words.next().and_then(|value| value.parse::<u64>().ok()) else {
+            continue;
+        };
+
+        match (word, side) {
+            ("depth", _) => limits.depth = value as u32,
+            ("movetime", _) => limits.time = Some(Duration::from_millis(value)),
+            ("wtime", Side::White) | ("btime", Side::Black) => {
+                clock = Some(Duration::from_millis(value))
+            }
+            ("winc", Side::White) | ("binc", Side::Black) => {
+                increment = Duration::from_millis(value)
+            }
+            ("movestogo", _) => moves_to_go = value.max(1),
+            _ => {}
+        }
+    }
+
+    // spread the time left over the moves to go, keeping a little in hand
+    if let Some(clock) = clock.filter(|_| limits.time.is_none() && !infinite) {
+        let budget = clock / moves_to_go as u32 + increment / 2;
+        let reserve = Duration::from_millis(50);
+
+        limits.time = Some(budget.min(clock.saturating_sub(reserve)));
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let search = {
+        let stop = stop.clone();
+
+        thread::spawn(move || {
+            let best = engine::search(&position, limits, &stop, |info| {
+                println!("{}", report(info))
+            });
+
+            // an infinite search only answers once it is stopped
+            while infinite && !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            match best {
+                Some(mv) => println!("bestmove {}", mv),
+                None => println!("bestmove 0000"),
+            }
+        })
+    };
+
+    (stop, search)
+}
+
+/// The `info` line of a completed depth.
+fn report(info: &Info) -> String {
+    let score = match Score::new(info.score) {
+        Score::Centipawns(centipawns) => format!("cp {}", centipawns),
+        Score::Mate(moves) => format!("mate {}", moves),
+    };
+
+    let millis = info.time.as_millis().max(1);
+    let pv: Vec<String> = info.pv.iter().map(|mv| mv.to_string()).collect();
+
+    format!(
+        "info depth {} score {} nodes {} nps {} time {} pv {}",
+        info.depth,
+        score,
+        info.nodes,
+        info.nodes as u128 * 1000 / millis,
+        millis,
+        pv.join(" ")
+    )
+}
+
+/// How long another engine may take to answer `uci` and `isready`.
+const HANDSHAKE: Duration = Duration::from_secs(5);
+
+/// Another engine speaking UCI, running as a child process.
+pub struct External {
+    name: String,
+    process: Child,
+    input: ChildStdin,
+    /// The lines the engine prints, read on their own thread so waiting for
+    /// them can give up.
+    output: Receiver<String>,
+}
+
+impl External {
+    /// Starts the engine at `path` and waits for it to be ready, for a few
+    /// seconds at most.
+    pub fn spawn(path: &Path) -> io::Result<External> {
+        let mut process = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let input = process.stdin.take().unwrap();
+        let output = read_lines(process.stdout.take().unwrap());
+
+        let mut engine = External {
+            name: path.display().to_string(),
+            process,
+            input,
+            output,
+        };
+
+        if let Err(e) = engine.handshake() {
+            // an engine that does not answer would not quit either
+            let _ = engine.process.kill();
+            return Err(e);
+        }
+
+        Ok(engine)
+    }
+
+    /// Introduces ourselves with `uci` and waits for the engine to be ready.
+    fn handshake(&mut self) -> io::Result<()> {
+        let deadline = Instant::now() + HANDSHAKE;
+
+        self.send("uci")?;
+
+        loop {
+            match self.read_until(Some(deadline))? {
+                line if line == "uciok" => break,
+                line => {
+                    if let Some(name) = line.strip_prefix("id name ") {
+                        self.name = name.to_string();
+                    }
+                }
+            }
+        }
+
+        self.send("isready")?;
+        while self.read_until(Some(deadline))? != "readyok" {}
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Searches the position `fen` within `limits`, for the best move and
+    /// the last score the engine reported.
+    pub fn analyse(
+        &mut self,
+        fen: &str,
+        limits: Limits,
+    ) -> io::Result<(Option<Move>, Option<Score>)> {
+        self.send(&format!("position fen {}", fen))?;
+
+        match limits.time {
+            Some(time) => self.send(&format!("go movetime {}", time.as_millis()))?,
+            None => self.send(&format!("go depth {}", limits.depth))?,
+        }
+
+        let mut score = None;
+
+        loop {
+            let line = self.read()?;
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("info") => {
+                    while let Some(word) = words.next() {
+                        if word != "score" {
+                            continue;
+                        }
+
+                        let value = words.next().zip(words.next());
+                        score = match value.map(|(kind, value)| (kind, value.parse())) {
+                            Some(("cp", Ok(value))) => Some(Score::Centipawns(value)),
+                            Some(("mate", Ok(value))) => Some(Score::Mate(value)),
+                            _ => score,
+                        };
+                    }
+                }
+                // `(none)` or `0000` when there is no legal move
+                Some("bestmove") => {
+                    return Ok((words.next().and_then(|mv| mv.parse().ok()), score))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.input, "{}", command)?;
+        self.input.flush()
+    }
+
+    fn read(&mut self) -> io::Result<String> {
+        self.read_until(None)
+    }
+
+    /// The next line the engine prints, waiting until `deadline` at most.
+    fn read_until(&mut self, deadline: Option<Instant>) -> io::Result<String> {
+        let line = match deadline {
+            Some(deadline) => self
+                .output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => self.output.recv().map_err(RecvTimeoutError::from),
+        };
+
+        line.map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "the engine did not get ready within {} seconds",
+                    HANDSHAKE.as_secs()
+                ),
+            ),
+            RecvTimeoutError::Disconnected => {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "the engine quit")
+            }
+        })
+    }
+}
+
+/// Reads the lines of `output` on a new thread, until it is closed.
+fn read_lines(output: ChildStdout) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            if sender.send(line.trim().to_string()).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+impl Drop for External {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,215 @@
+//! Replays the games of a PGN file on the board, one move at a time.
+
+use std::{fs, io::Write, path::Path};
+
+use chess_lib::chess::{Board, Error};
+use colored::*;
+
+use crate::{draw, pgn, position::Position, Highlights, Side};
+
+/// The move list is wrapped to this width.
+const WIDTH: usize = 72;
+
+pub fn run(path: &Path) -> Result<(), Error> {
+    let games = match pgn::parse(&fs::read_to_string(path)?) {
+        Ok(games) if games.is_empty() => {
+            println!(
+                "{}",
+                format!("There are no games in {}", path.display()).red()
+            );
+            return Ok(());
+        }
+        Ok(games) => games,
+        Err(e) => {
+            println!(
+                "{}",
+                format!("Could not read {}: {}", path.display(), e).red()
+            );
+            return Ok(());
+        }
+    };
+
+    // a single game needs no picking
+    if let [game] = games.as_slice() {
+        return replay(game);
+    }
+
+    let mut error: Option<String> = None;
+
+    loop {
+        // clear screen
+        print!("{}[2J", 27 as char);
+
+        if let Some(error) = error.take() {
+            println!("\n{}\n", error.red());
+        }
+
+        for (number, game) in (1..).zip(&games) {
+            println!("{:>3}. {}", number, title(game));
+        }
+
+        println!("\n{}", "game number, quit".dimmed());
+        print!("> ");
+
+        // flush stdout
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+
+        match input.trim() {
+            "" => {}
+            "q" | "quit" | "exit" => return Ok(()),
+            number => match number.parse::<usize>() {
+                Ok(number) if (1..=games.len()).contains(&number) => replay(&games[number - 1])?,
+                _ => error = Some(format!("pick a game from 1 to {}", games.len())),
+            },
+        }
+    }
+}
+
+/// A one line summary of `game`, e.g. `Kasparov - Topalov, Wijk aan Zee
+/// 1999.01.20, 1-0`.
+fn title(game: &pgn::Game) -> String {
+    let tag = |name| game.tag(name).unwrap_or("?");
+
+    format!(
+        "{} - {}, {} {}, {}",
+        tag("White"),
+        tag("Black"),
+        tag("Event"),
+        tag("Date"),
+        tag("Result")
+    )
+}
+
+/// Steps through `game` until the user goes back.
+fn replay(game: &pgn::Game) -> Result<(), Error> {
+    // the position after every move, starting with the one before any
+    let mut board = Board::from_fen(&game.start)?;
+    let mut positions = vec![board.to_fen()];
+
+    for (mv, _) in &game.moves {
+        board.move_piece(&mv.to_string())?;
+        positions.push(board.to_fen());
+    }
+
+    // plies are counted from the start of a standard game, so that even
+    // ones are white's
+    let first_ply = game.start.parse::<Position>().map_or(2, |position| {
+        position.fullmove as usize * 2 + usize::from(position.turn() == Side::Black)
+    });
+
+    // moves played so far
+    let mut current = 0;
+    let mut flipped = false;
+    let mut error: Option<String> = None;
+
+    loop {
+        // clear screen
+        print!("{}[2J", 27 as char);
+
+        println!("{}", title(game).bold());
+
+        if let Some(error) = error.take() {
+            println!("\n{}\n", error.red());
+        }
+
+        let side = if flipped { Side::Black } else { Side::White };
+        let board = Board::from_fen(&positions[current])?;
+        let last_move = current.checked_sub(1).map(|index| game.moves[index].0);
+        draw(&board, side, &[], &Highlights::new(&board, last_move));
+
+        println!();
+        for line in move_list(game, first_ply, current) {
+            println!("{}", line);
+        }
+
+        if current == game.moves.len() {
+            if let Some(result) = game.tag("Result") {
+                println!("\n{}", result.bold());
+            }
+        }
+
+        println!(
+            "\n{}",
+            "Enter or next, prev, first, last, goto <move>, flip, quit".dimmed()
+        );
+        print!("> ");
+
+        // flush stdout
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+
+        let mut cmd = input.split_whitespace();
+
+        match cmd.next() {
+            None | Some("n" | "next") => current = (current + 1).min(game.moves.len()),
+            Some("p" | "prev") => current = current.saturating_sub(1),
+            Some("f" | "first") => current = 0,
+            Some("l" | "last") => current = game.moves.len(),
+            Some("flip") => flipped = !flipped,
+            Some("q" | "quit" | "exit") => return Ok(()),
+
+            // to the position after white's move of that number
+            Some("goto") => match cmd.next().map(str::parse::<usize>) {
+                Some(Ok(number)) => {
+                    current = (number * 2 + 1)
+                        .saturating_sub(first_ply)
+                        .min(game.moves.len())
+                }
+                _ => error = Some("expected a move number after goto".to_string()),
+            },
+
+            Some(other) => error = Some(format!("unknown command {:?}", other)),
+        }
+    }
+}
+
+/// The numbered moves of `game` wrapped into lines, with the last of the
+/// `current` moves played highlighted.
+fn move_list(game: &pgn::Game, first_ply: usize, current: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut width = 0;
+
+    for (index, (_, san)) in game.moves.iter().enumerate() {
+        let ply = first_ply + index;
+        let number = match (ply % 2, index) {
+            (0, _) => format!("{}. ", ply / 2),
+            (_, 0) => format!("{}... ", ply / 2),
+            _ => String::new(),
+        };
+
+        let token = if index + 1 == current {
+            format!("{}{}", number, san.reversed())
+        } else {
+            format!("{}{}", number, san)
+        };
+
+        // escape codes take no room on the terminal
+        let length = number.len() + san.len();
+
+        if width > 0 && width + 1 + length > WIDTH {
+            lines.push(std::mem::take(&mut line));
+            width = 0;
+        }
+
+        if width > 0 {
+            line.push(' ');
+            width += 1;
+        }
+
+        line.push_str(&token);
+        width += length;
+    }
+
+    lines.push(line);
+    lines
+}
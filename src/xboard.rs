@@ -0,0 +1,148 @@
+//! The Chess Engine Communication Protocol of XBoard and WinBoard, for the
+//! tools that do not speak UCI.
+//!
+//! Like in the interactive modes the game is kept on a `Board` with its
+//! `History`, the moves of the computer come from a `Computer` and `Outcome`
+//! decides when the game is over. Commands that make no difference to the
+//! engine are ignored.
+
+use std::{
+    io::{self, BufRead},
+    time::Duration,
+};
+
+use chess_lib::chess::{Board, Error};
+
+use crate::{board_from_fen, history::History, position::Position, Computer, Outcome, Side};
+
+pub fn run(mut computer: Computer) -> Result<(), Error> {
+    let mut board = Board::default_board()?;
+    let mut history = History::new(&board);
+
+    // the side the engine plays, none in force mode
+    let mut engine = Some(Side::Black);
+
+    let mut clock: Option<Duration> = None;
+    let mut move_time: Option<Duration> = None;
+    let mut depth: Option<u32> = None;
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let (command, argument) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+        match command {
+            "protover" => println!(
+                "feature myname=\"chess-cli {}\" usermove=1 setboard=1 ping=1 \
+                 sigint=0 sigterm=0 colors=0 done=1",
+                env!("CARGO_PKG_VERSION")
+            ),
+
+            "new" => {
+                board = Board::default_board()?;
+                history = History::new(&board);
+                engine = Some(Side::Black);
+                move_time = None;
+                depth = None;
+            }
+
+            "force" | "result" => engine = None,
+
+            "go" => engine = Some(Side::to_move(&board)),
+
+            "playother" => engine = Some(Side::to_move(&board).opponent()),
+
+            "usermove" => {
+                let before = board.to_fen();
+
+                if let Err(e) = board.move_piece(argument) {
+                    println!("Illegal move ({}): {}", e, argument);
+                    continue;
+                }
+
+                history.push(before, argument, &board);
+
+                if game_over(&board, &history) {
+                    engine = None;
+                }
+            }
+
+            "setboard" => match board_from_fen(argument) {
+                Ok(position) => {
+                    board = position;
+                    history = History::new(&board);
+                }
+                Err(e) => println!("tellusererror Illegal position: {}", e),
+            },
+
+            // in centiseconds
+            "time" => {
+                clock = argument
+                    .parse()
+                    .ok()
+                    .map(Duration::from_millis)
+                    .map(|time| time * 10)
+            }
+
+            // only our own time matters for the search
+            "otim" => {}
+
+            "st" => move_time = argument.parse().ok().map(Duration::from_secs),
+
+            "sd" => depth = argument.parse().ok(),
+
+            "ping" => println!("pong {}", argument),
+
+            "quit" => break,
+
+            _ => continue,
+        }
+
+        if engine != Some(Side::to_move(&board)) {
+            continue;
+        }
+
+        // e.g. `go` in a position set up as mate, there is nothing to search
+        if game_over(&board, &history) {
+            engine = None;
+            continue;
+        }
+
+        let mut limits = computer.limits();
+
+        if let Some(depth) = depth {
+            limits.depth = depth;
+        }
+
+        // leave enough time for the rest of the game
+        let budget = clock.map(|clock| clock / 30);
+        limits.time = match (move_time, budget) {
+            (Some(time), _) => Some(time),
+            (None, Some(budget)) => limits.time.map(|time| time.min(budget)),
+            (None, None) => limits.time,
+        };
+
+        let mv = computer.best_move(&board, limits)?.to_string();
+        let before = board.to_fen();
+        board.move_piece(&mv)?;
+        history.push(before, &mv, &board);
+        println!("move {}", mv);
+
+        if game_over(&board, &history) {
+            engine = None;
+        }
+    }
+
+    Ok(())
+}
+
+/// Claims the result when the game on `board`, reached through `history`, is
+/// over.
+fn game_over(board: &Board, history: &History) -> bool {
+    match Outcome::after(&Position::of(board), history) {
+        Some(outcome) => {
+            println!("{} {{{}}}", outcome.score(), outcome.reason());
+            true
+        }
+        None => false,
+    }
+}